@@ -0,0 +1,199 @@
+use rusqlite::{Error, Result};
+
+use crate::parse_query::QueryDetails;
+
+/// Rows of a full scan if the table doesn't specify `estimated_rows`
+const DEFAULT_ESTIMATED_ROWS: f64 = 1000.;
+/// Fraction of the rows that remains per constraint applied by the server
+const SELECTIVITY: f64 = 0.1;
+/// Assumed number of values of an IN list
+const IN_LIST_VALUES: f64 = 10.;
+/// A request is much more expensive than decoding a row
+const REQUEST_COST: f64 = 1000.;
+const ROW_COST: f64 = 10.;
+
+/// Planner hints of a table, `estimated_rows=N` and `unique_key=COLUMN[,COLUMN]`
+#[derive(Default, Clone, Debug, PartialEq)]
+pub struct Estimates {
+    /// Number of rows returned without constraints
+    pub estimated_rows: Option<f64>,
+    /// Variable or result columns that identify a row
+    pub unique_key: Vec<String>,
+    /// Indices of the key columns, set by `resolve`
+    pub unique_key_cols: Vec<usize>,
+}
+
+/// Constraints and limits that a plan passes on to the server
+#[derive(Default, Debug)]
+pub struct Plan {
+    /// Columns with an `=` constraint
+    pub eq_columns: Vec<usize>,
+    /// Columns with an IN list
+    pub in_columns: Vec<usize>,
+    /// Number of other constraints
+    pub filters: usize,
+    /// The SQL LIMIT, None if it isn't passed on or unknown
+    pub limit: Option<f64>,
+    /// Page size if the list is paginated
+    pub page_size: Option<i64>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Estimate {
+    pub cost: f64,
+    pub rows: f64,
+    /// At most one row is returned
+    pub unique: bool,
+}
+
+impl Estimates {
+    /// Parses the `estimated_rows` module option
+    pub fn set_estimated_rows(&mut self, value: &str) -> Result<()> {
+        match value.parse::<f64>() {
+            Ok(rows) if rows.is_finite() && rows >= 0. => {
+                self.estimated_rows = Some(rows);
+                Ok(())
+            }
+            _ => Err(Error::ModuleError(format!(
+                "invalid `estimated_rows`: {value}"
+            ))),
+        }
+    }
+
+    /// Parses the `unique_key` module option, `,` separated column names
+    pub fn set_unique_key(&mut self, value: &str) -> Result<()> {
+        self.unique_key = value
+            .split(',')
+            .map(|it| it.trim())
+            .filter(|it| !it.is_empty())
+            .map(|it| it.to_owned())
+            .collect();
+        if self.unique_key.is_empty() {
+            return Err(Error::ModuleError(format!("invalid `unique_key`: {value}")));
+        }
+        Ok(())
+    }
+
+    /// Resolves the key columns, results come first followed by the variables
+    pub fn resolve(&mut self, details: &QueryDetails) -> Result<()> {
+        self.unique_key_cols = self
+            .unique_key
+            .iter()
+            .map(|column| {
+                details
+                    .results
+                    .iter()
+                    .map(|it| it.to_string())
+                    .chain(details.variables.iter().map(|it| it.name.clone()))
+                    .position(|it| it == *column)
+                    .ok_or_else(|| {
+                        Error::ModuleError(format!("unknown `unique_key` column `{column}`"))
+                    })
+            })
+            .collect::<Result<_>>()?;
+        Ok(())
+    }
+
+    /// Estimates the rows and the cost of fetching them, the cost is dominated by the requests
+    pub fn estimate(&self, plan: &Plan) -> Estimate {
+        let key_in = |cols: &[usize]| {
+            !self.unique_key_cols.is_empty()
+                && self
+                    .unique_key_cols
+                    .iter()
+                    .all(|it| plan.eq_columns.contains(it) || cols.contains(it))
+        };
+        let unique = key_in(&[]);
+        let mut rows = if unique {
+            1.
+        } else if key_in(&plan.in_columns) {
+            // one row per value
+            IN_LIST_VALUES
+        } else {
+            let constraints = plan.eq_columns.len() + plan.in_columns.len() + plan.filters;
+            let rows = self.estimated_rows.unwrap_or(DEFAULT_ESTIMATED_ROWS)
+                * SELECTIVITY.powi(constraints as i32)
+                * IN_LIST_VALUES.powi(plan.in_columns.len() as i32);
+            rows.max(1.)
+        };
+        if let Some(limit) = plan.limit {
+            rows = rows.min(limit);
+        }
+        let requests = match plan.page_size {
+            Some(page_size) => (rows / page_size as f64).ceil().max(1.),
+            None => 1.,
+        };
+        Estimate {
+            cost: requests * REQUEST_COST + rows * ROW_COST,
+            rows,
+            unique,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::parse_query::parse;
+
+    use super::{Estimate, Estimates, Plan};
+
+    #[test]
+    fn test_estimate() {
+        let query = r#"query User($user_id: ID, $team: String) {
+          users(id: $user_id, team: $team) { id name }
+        }"#;
+        let details = parse(query).unwrap();
+        let mut estimates = Estimates::default();
+        estimates.set_estimated_rows("5000").unwrap();
+        estimates.set_unique_key("user_id").unwrap();
+        estimates.resolve(&details).unwrap();
+        assert_eq!(estimates.unique_key_cols, vec![2]);
+
+        let scan = estimates.estimate(&Plan::default());
+        assert_eq!(scan.rows, 5000.);
+        let filtered = estimates.estimate(&Plan {
+            eq_columns: vec![3],
+            ..Plan::default()
+        });
+        assert_eq!(filtered.rows, 500.);
+        assert!(filtered.cost < scan.cost);
+        let lookup = estimates.estimate(&Plan {
+            eq_columns: vec![2],
+            ..Plan::default()
+        });
+        assert_eq!(
+            lookup,
+            Estimate {
+                cost: 1010.,
+                rows: 1.,
+                unique: true,
+            }
+        );
+        let in_list = estimates.estimate(&Plan {
+            in_columns: vec![2],
+            ..Plan::default()
+        });
+        assert_eq!(in_list.rows, 10.);
+        assert!(!in_list.unique);
+
+        // every page is a request
+        let paginated = estimates.estimate(&Plan {
+            page_size: Some(100),
+            ..Plan::default()
+        });
+        assert_eq!(paginated.cost, 50. * 1000. + 5000. * 10.);
+        let limited = estimates.estimate(&Plan {
+            page_size: Some(100),
+            limit: Some(20.),
+            ..Plan::default()
+        });
+        assert_eq!(limited.rows, 20.);
+        assert_eq!(limited.cost, 1000. + 20. * 10.);
+
+        assert!(Estimates::default().set_estimated_rows("many").is_err());
+        assert!(Estimates::default().set_unique_key(" , ").is_err());
+        let mut estimates = Estimates::default();
+        estimates.set_unique_key("name, uuid").unwrap();
+        assert!(estimates.resolve(&details).is_err());
+    }
+}
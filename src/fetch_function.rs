@@ -0,0 +1,61 @@
+use rusqlite::{functions::FunctionFlags, Connection, Error, Result};
+use serde_json::{json, Value};
+
+use crate::transport::Transport;
+
+/// Register the `graphql_fetch` scalar function.
+/// ```sql
+/// SELECT json_extract(graphql_fetch(:url, :query, :variables_json, :headers_json), '$.films')
+/// ```
+/// Returns the `data` of the response as JSON text, a response with `errors` raises an error.
+/// `variables_json` (a JSON object with the query variables) and `headers_json` (a JSON object
+/// of HTTP headers) are optional.
+/// The `bearer_token`, `basic_auth` and `api_key` options of the `graphql` module aren't
+/// supported, pass credentials as headers, e.g. `'{"Authorization": "Bearer TOKEN"}'`.
+/// Each call sends a request, responses aren't cached.
+pub fn load_function(conn: &Connection) -> Result<()> {
+    // requests have side effects, e.g. if the query is a mutation
+    let flags = FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DIRECTONLY;
+    conn.create_scalar_function("graphql_fetch", -1, flags, |ctx| {
+        if !(2..=4).contains(&ctx.len()) {
+            return Err(Error::ModuleError(
+                "graphql_fetch expects the arguments url, query [, variables [, headers]]"
+                    .to_owned(),
+            ));
+        }
+        let url: String = ctx.get(0)?;
+        let query: String = ctx.get(1)?;
+        let variables: Option<String> = if ctx.len() > 2 { ctx.get(2)? } else { None };
+        let headers: Option<String> = if ctx.len() > 3 { ctx.get(3)? } else { None };
+        let data = fetch(url, &query, variables.as_deref(), headers.as_deref())?;
+        Ok(data.to_string())
+    })
+}
+
+/// Sends an ad-hoc query and returns the `data` of the response.
+/// `variables` and `headers` are JSON objects.
+pub fn fetch(
+    url: String,
+    query: &str,
+    variables: Option<&str>,
+    headers: Option<&str>,
+) -> Result<Value> {
+    let variables = match variables {
+        Some(variables) => serde_json::from_str::<Value>(variables)
+            .ok()
+            .filter(|it| it.is_object())
+            .ok_or_else(|| Error::ModuleError("`variables` must be a JSON object".to_owned()))?,
+        None => json!({}),
+    };
+    let mut transport = Transport::default();
+    transport.url = url;
+    if let Some(headers) = headers {
+        transport.set_option("headers", headers)?;
+    }
+    transport.validate()?;
+    let mut res = transport.post(&json!({
+      "query": query,
+      "variables": variables
+    }))?;
+    Ok(res.get_mut("data").map(Value::take).unwrap_or_default())
+}
@@ -0,0 +1,284 @@
+use rusqlite::{vtab::IndexConstraintOp, Error, Result};
+
+use crate::{
+    optimize_query::{find_argument, parse_type, InjectedArgument},
+    parse_query::{QueryDetails, VariableType},
+};
+
+/// SQL operators that can be mapped to filter arguments
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operator {
+    Eq,
+    Ne,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+    Like,
+    Glob,
+    /// Binds the values of an IN list, or the value of `=`, to a list argument
+    In,
+}
+
+impl Operator {
+    fn parse(value: &str) -> Option<Operator> {
+        match value.to_uppercase().as_str() {
+            "=" | "==" => Some(Operator::Eq),
+            "!=" | "<>" => Some(Operator::Ne),
+            ">" => Some(Operator::Gt),
+            ">=" => Some(Operator::Ge),
+            "<" => Some(Operator::Lt),
+            "<=" => Some(Operator::Le),
+            "LIKE" => Some(Operator::Like),
+            "GLOB" => Some(Operator::Glob),
+            "IN" => Some(Operator::In),
+            _ => None,
+        }
+    }
+
+    pub fn from_constraint(op: IndexConstraintOp) -> Option<Operator> {
+        match op {
+            IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_EQ => Some(Operator::Eq),
+            IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_NE => Some(Operator::Ne),
+            IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_GT => Some(Operator::Gt),
+            IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_GE => Some(Operator::Ge),
+            IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_LT => Some(Operator::Lt),
+            IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_LE => Some(Operator::Le),
+            IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_LIKE => Some(Operator::Like),
+            IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_GLOB => Some(Operator::Glob),
+            _ => None,
+        }
+    }
+}
+
+/// Parses an argument of the endpoint like `filter.createdAt` or an argument of a nested field
+/// like `films(filter.director)` into the field path and the argument path
+pub fn parse_argument_target(value: &str) -> Option<(Vec<String>, Vec<String>)> {
+    let (field, argument) = match value.split_once('(') {
+        Some((field, argument)) => (field, argument.trim_end().strip_suffix(')')?),
+        None => ("", value),
+    };
+    let split_path = |path: &str| -> Vec<String> {
+        path.split('.')
+            .map(|it| it.trim())
+            .filter(|it| !it.is_empty())
+            .map(|it| it.to_owned())
+            .collect()
+    };
+    let argument_path = split_path(argument);
+    if argument_path.is_empty() {
+        return None;
+    }
+    Some((split_path(field), argument_path))
+}
+
+/// Maps a SQL constraint to a (nested) field argument, e.g.
+/// `filter='created_at >= filter.createdAt.afterOrEqualTo'` or
+/// `filter='films_director = films(filter.director)'`
+#[derive(Clone, Debug, PartialEq)]
+pub struct FilterMapping {
+    /// Variable or result column
+    pub column: String,
+    pub operator: Operator,
+    /// Path of the field relative to the endpoint, empty for the endpoint itself
+    pub field_path: Vec<String>,
+    /// Argument name followed by the keys of nested input objects
+    pub argument_path: Vec<String>,
+    /// GraphQL type of the argument, defaults to the type of the column's variable or the
+    /// introspected type of the result column
+    pub var_type: Option<VariableType>,
+
+    /// Index of the column, set by `resolve`
+    pub column_idx: usize,
+    /// Variable that receives the constraint value
+    pub variable: String,
+    /// Argument that needs to be added to the query when the filter is used
+    pub injected: Option<InjectedArgument>,
+}
+
+impl FilterMapping {
+    /// Parses a `filter` module option of the form `column OP [field.path(]argument.path[)][: Type]`
+    pub fn from_option(value: &str) -> Result<FilterMapping> {
+        let invalid = || {
+            Error::ModuleError(format!(
+                "invalid `filter` `{value}`, expected `column OP [field(]argument[)][: Type]`"
+            ))
+        };
+        let mut parts = value.split_whitespace();
+        let (Some(column), Some(operator)) = (parts.next(), parts.next()) else {
+            return Err(invalid());
+        };
+        let operator = Operator::parse(operator).ok_or_else(invalid)?;
+        let argument = parts.collect::<Vec<_>>().join(" ");
+        let (argument, var_type) = match argument.split_once(':') {
+            Some((argument, var_type)) => (argument.trim(), Some(var_type.trim())),
+            None => (argument.trim(), None),
+        };
+        if var_type == Some("") {
+            return Err(invalid());
+        }
+        let (field_path, argument_path) = parse_argument_target(argument).ok_or_else(invalid)?;
+        Ok(FilterMapping {
+            column: column.to_owned(),
+            operator,
+            field_path,
+            argument_path,
+            var_type: var_type.map(|it| VariableType::from(&parse_type(it))),
+            column_idx: 0,
+            variable: String::new(),
+            injected: None,
+        })
+    }
+
+    /// Resolves the column and binds the argument to a query variable.
+    /// `index` makes the name of an injected variable unique.
+    pub fn resolve(&mut self, query: &str, details: &QueryDetails, index: usize) -> Result<()> {
+        let default_type = if let Some(result_idx) = details
+            .results
+            .iter()
+            .position(|it| it.to_string() == self.column)
+        {
+            self.column_idx = result_idx;
+            details.results[result_idx]
+                .graphql_type
+                .as_ref()
+                .map(|it| VariableType::Named(it.clone()))
+        } else if let Some(var_idx) = details
+            .variables
+            .iter()
+            .position(|it| it.name == self.column)
+        {
+            self.column_idx = details.results.len() + var_idx;
+            // the constraint value is optional even if the variable is required
+            Some(match &details.variables[var_idx].var_type {
+                VariableType::NonNull(inner) => inner.as_ref().clone(),
+                var_type => var_type.clone(),
+            })
+        } else {
+            return Err(Error::ModuleError(format!(
+                "unknown `filter` column `{}`",
+                self.column
+            )));
+        };
+        if self.var_type.is_none() {
+            self.var_type = default_type;
+        }
+        if self.var_type.is_none() {
+            return Err(Error::ModuleError(format!(
+                "the type of `filter` column `{0}` is unknown, use `introspect=1` or specify \
+                 it, e.g. `{0} = argument: String`",
+                self.column
+            )));
+        }
+        // IN binds a list of values
+        if self.operator == Operator::In && !self.value_type().is_list() {
+            self.var_type = Some(VariableType::List(Box::new(self.value_type().clone())));
+        }
+
+        let existing = find_argument(query, &self.field_path, &self.argument_path)
+            .map_err(|err| Error::ModuleError(err.to_string()))?;
+        // an argument bound to a variable keeps it, the variable would be unused otherwise
+        if let Some(variable) = existing.as_deref().and_then(|it| it.strip_prefix('$')) {
+            self.variable = variable.to_owned();
+            self.injected = None;
+            return Ok(());
+        }
+        self.variable = format!("_apisql_filter_{index}");
+        self.injected = Some(InjectedArgument {
+            field_path: self.field_path.clone(),
+            argument_path: self.argument_path.clone(),
+            variable: self.variable.clone(),
+            var_type: self
+                .var_type
+                .as_ref()
+                .map(|it| it.to_string())
+                .unwrap_or_default(),
+            keep_existing: false,
+            literal: None,
+        });
+        Ok(())
+    }
+
+    /// Type of the constraint value
+    pub fn value_type(&self) -> &VariableType {
+        self.var_type.as_ref().expect("filter mapping not resolved")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{optimize_query::optimize_query, parse_query::parse};
+
+    use super::{FilterMapping, Operator};
+
+    #[test]
+    fn test_filter_mapping() {
+        let query = r#"query Orders($created_at: String!) {
+          orders(filter: {createdAt: {equalTo: $created_at}}) { id }
+        }"#;
+        let details = parse(query).unwrap();
+
+        let mut mapping =
+            FilterMapping::from_option("created_at >= filter.createdAt.afterOrEqualTo").unwrap();
+        assert_eq!(mapping.operator, Operator::Ge);
+        mapping.resolve(query, &details, 0).unwrap();
+        assert_eq!(mapping.column_idx, 1);
+        assert_eq!(mapping.variable, "_apisql_filter_0");
+        let injected = [mapping.injected.clone().unwrap()];
+        let request = optimize_query(query, u64::MAX, &[], &injected).unwrap();
+        assert!(request.contains("query Orders($created_at: String!, $_apisql_filter_0: String)"));
+        assert!(request.contains(
+            "filter: {createdAt: {afterOrEqualTo: $_apisql_filter_0, equalTo: $created_at}}"
+        ));
+
+        // the argument is already bound to a variable
+        let mut mapping =
+            FilterMapping::from_option("created_at = filter.createdAt.equalTo: Date").unwrap();
+        mapping.resolve(query, &details, 1).unwrap();
+        assert_eq!(mapping.variable, "created_at");
+        assert_eq!(mapping.injected, None);
+
+        assert!(FilterMapping::from_option("created_at ~ filter").is_err());
+        let mut mapping = FilterMapping::from_option("total = filter.total").unwrap();
+        assert!(mapping.resolve(query, &details, 2).is_err());
+    }
+
+    #[test]
+    fn test_result_column_filter() {
+        let query = r#"query Films {
+          allFilms { films { title director } }
+        }"#;
+        let mut details = parse(query).unwrap();
+
+        // the type of a result column is only known after introspection
+        let mut mapping =
+            FilterMapping::from_option("films_director = films(filter.director)").unwrap();
+        assert!(mapping.resolve(query, &details, 0).is_err());
+        details.results[1].graphql_type = Some("String".to_owned());
+        mapping.resolve(query, &details, 0).unwrap();
+        assert_eq!(mapping.column_idx, 1);
+        assert_eq!(mapping.field_path, vec!["films".to_owned()]);
+
+        let injected = [mapping.injected.clone().unwrap()];
+        let request = optimize_query(query, u64::MAX, &[], &injected).unwrap();
+        assert!(request.contains("query Films($_apisql_filter_0: String)"));
+        assert!(request.contains("films(filter: {director: $_apisql_filter_0})"));
+
+        let mut mapping =
+            FilterMapping::from_option("films_title LIKE films(filter.titleLike): String").unwrap();
+        mapping.resolve(query, &details, 1).unwrap();
+        assert_eq!(mapping.operator, Operator::Like);
+        assert_eq!(mapping.column_idx, 0);
+
+        // IN binds a list of the column type
+        let mut mapping =
+            FilterMapping::from_option("films_director IN films(filter.directors)").unwrap();
+        assert_eq!(mapping.operator, Operator::In);
+        mapping.resolve(query, &details, 2).unwrap();
+        assert_eq!(mapping.injected.unwrap().var_type, "[String]");
+        let mut mapping =
+            FilterMapping::from_option("films_director IN films(filter.directors): [ID!]").unwrap();
+        mapping.resolve(query, &details, 3).unwrap();
+        assert_eq!(mapping.injected.unwrap().var_type, "[ID!]");
+    }
+}
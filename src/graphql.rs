@@ -1,14 +1,17 @@
+use std::collections::VecDeque;
 use std::marker::PhantomData;
 use std::os::raw::c_int;
 
 use std::str;
 
+use fallible_iterator::FallibleIterator;
 use rusqlite::{
     ffi,
-    types::Null,
+    types::{Value as SqlValue, ValueRef},
     vtab::{
-        parameter, read_only_module, Context, CreateVTab, IndexInfo, VTab, VTabConfig,
-        VTabConnection, VTabCursor, VTabKind, Values,
+        dequote, parse_boolean, update_module, Context, CreateVTab, Filters, IndexConstraintOp,
+        IndexFlags, IndexInfo, Inserts, UpdateVTab, Updates, VTab, VTabConfig, VTabConnection,
+        VTabCursor, VTabKind, Values,
     },
     Connection, Error, Result,
 };
@@ -16,11 +19,22 @@ use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 
 use crate::{
-    optimize_query::optimize_query,
-    parse_query::{parse, QueryDetails, ResultPath},
+    estimate::{Estimates, Plan},
+    fetch_function,
+    filter::{FilterMapping, Operator},
+    introspect::{Schema, INTROSPECTION_QUERY},
+    mutation::Mutation,
+    optimize_query::{batch_alias, batch_query, batch_variable, optimize_query},
+    order_by::OrderBy,
+    pagination::{LimitArguments, Pagination},
+    parse_query::{parse, QueryDetails},
+    query_function,
+    stream::{Event, ResponseStream},
+    transport::Transport,
 };
 
-/// Register the "graphql" module.
+/// Register the "graphql" module, the `graphql_query` table-valued function and the
+/// `graphql_fetch` scalar function.
 /// ```sql
 /// CREATE VIRTUAL TABLE vtab USING graphql(
 /// graphql(url='http://localhost:8000/graphql',
@@ -28,17 +42,109 @@ use crate::{
 ///   operationName=OPERATION_NAME -- Query GraphGL operation name
 ///   query=GRAPHQL_QUERY -- The underlying graphql query
 ///   variableNames='[]' -- JSON array of input variable names
+///   introspect=1 -- Optional, validate the query against the server schema and use the schema
+///                   types for the columns
+///   header_NAME=VALUE -- Optional, HTTP header sent with every request, e.g. header_X-Tenant=abc
+///   headers='{"NAME": "VALUE"}' -- Optional, JSON object of HTTP headers
+///   bearer_token=TOKEN -- Optional, sent as `Authorization: Bearer TOKEN`
+///   basic_auth=USER:PASSWORD -- Optional, HTTP basic authentication
+///   api_key=KEY -- Optional, sent in the `X-API-Key` header (see api_key_header=NAME)
+///   paginate=relay -- Optional, fetch all pages of a Relay connection, the query must select
+///                     `pageInfo { hasNextPage endCursor }`. Page size: relay(page_size=100)
+///   paginate=offset(page_size=100, offset_var=offset, limit_var=first) -- Optional, fetch pages
+///                     until a short page is returned. offset_var/limit_var name query variables
+///                     or endpoint arguments, e.g. page.offset
+///   limit_arg=first -- Optional, query variable or endpoint argument that receives the SQL LIMIT
+///   offset_arg=offset -- Optional, query variable or endpoint argument that receives the SQL
+///                     OFFSET. Not used with `paginate`, pages stop once the LIMIT is reached
+///   filter='COLUMN OP [FIELD(]ARGUMENT[)][: TYPE]' -- Optional, repeatable, passes a constraint on
+///                     a variable or result column to a (nested) argument of the endpoint or of
+///                     FIELD, e.g. filter='created_at >= filter.createdAt.afterOrEqualTo' or
+///                     filter='films_director = films(filter.director)'. OP is one of
+///                     =, !=, >, >=, <, <=, LIKE, GLOB or IN. TYPE defaults to the type of the
+///                     variable or the introspected type of the result column, IN binds a list
+///                     of a non-list TYPE, e.g. filter='id IN filter.ids', and receives all values
+///                     of an IN list or the value of `=`. SQLite re-checks the rows of other
+///                     operators on result columns, the server only narrows them down.
+///                     Variable columns only bind `=` constraints otherwise, other constraints
+///                     are evaluated by SQLite
+///   order_by_arg=ARGUMENT -- Optional, sort argument of the endpoint or of a field, e.g. sort or
+///                     films(orderBy)
+///   order_by='COLUMN=VALUE; ...' -- Optional, sort values of result columns, e.g.
+///                     order_by='nodes_createdAt={key: createdAt, desc: $desc}'. $desc is replaced
+///                     with true/false and $direction with ASC/DESC. ORDER BY clauses on these
+///                     columns are sorted by the server
+///   rows=PATH -- Optional, dot separated response keys of the list that becomes the rows, e.g.
+///                     rows=nodes.lines. Lists nested in the exploded list along PATH are
+///                     unnested, each inner item becomes a row with the outer values repeated.
+///                     The SQL LIMIT and OFFSET aren't passed on to the server
+///   json_columns=PATH[,PATH] -- Optional, dot separated response keys of fields that become a
+///                     single column of serialized JSON with their whole selection, e.g.
+///                     json_columns=nodes.customer,nodes.tags. The values are JSON text, wrap
+///                     them in `json()` to nest them as JSON in `json_object` and friends
+///   estimated_rows=N -- Optional, number of rows without constraints, used by the query planner
+///   unique_key=COLUMN[,COLUMN] -- Optional, variable or result columns that identify a row,
+///                     `=` constraints on all of them return at most one row
+///   insert_mutation=MUTATION -- Optional, mutation sent for each inserted row, e.g.
+///                     'mutation AddUser($name: String!) { addUser(name: $name) { id } }'
+///   update_mutation=MUTATION -- Optional, mutation sent for each updated row
+///   delete_mutation=MUTATION -- Optional, mutation sent for each deleted row, only the variable
+///                     of the `unique_key` column is bound
+///   error_types=TYPE[,TYPE] -- Optional, mutation result types that are errors, e.g.
+///                     RecordNotFound,ValidationError
+///
+/// Mutation variables are bound to the column values of the same name, NULL values are left
+/// out. `update_mutation` and `delete_mutation` require a single `unique_key` result column, it
+/// becomes the PRIMARY KEY of the table and identifies the rows.
+/// An integer ID returned by `insert_mutation` (the `unique_key` or `id` result) becomes the
+/// rowid and `last_insert_rowid()` of tables without `update_mutation` and `delete_mutation`.
+/// Inserts into a WITHOUT ROWID table, or without an integer ID, leave `last_insert_rowid()`
+/// unchanged. Returning one of the `error_types`, e.g. `... on RecordNotFound { message }`, fails
+/// the statement with the `message` of the fragment. Errors name the row by its `unique_key`.
+///
+/// Query variables are hidden columns. They are bound with `=` constraints or passed as
+/// arguments of a table-valued function, e.g. `SELECT * FROM auth_token('user', 'pw')`.
+///
+/// `IN` constraints on a variable column are fetched with a single request. A list variable
+/// receives all values, otherwise the endpoint is repeated once per value (without `paginate`).
+///
+/// Credentials and the url can reference secrets instead of containing them, e.g.
+/// `bearer_token=env:GRAPHQL_TOKEN` or `api_key=file:/run/secrets/api_key`. References are
+/// resolved when a request is sent and only the reference is stored in the database schema.
+/// Header values are sent as they are.
+///
+/// Trust model: the table options live in the database file, whoever can write it chooses the
+/// server that requests are sent to. A reference is therefore only resolved if the process
+/// allows it in the `APISQL_SECRET_REFERENCES` environment variable, e.g.
+/// `APISQL_SECRET_REFERENCES=env:GRAPHQL_TOKEN,file:/run/secrets`, other references fail.
 /// );
 /// ```
 pub fn load_module(conn: &Connection) -> Result<()> {
     let aux: Option<()> = None;
-    conn.create_module("graphql", read_only_module::<GraphQLTab>(), aux)
+    conn.create_module("graphql", update_module::<GraphQLTab>(), aux)?;
+    query_function::load_module(conn)?;
+    fetch_function::load_function(conn)
 }
 
 #[derive(Default, Clone)]
 struct Config {
-    url: String,
+    transport: Transport,
     query: String,
+    introspect: bool,
+    pagination: Pagination,
+    limit_arguments: LimitArguments,
+    filters: Vec<FilterMapping>,
+    order_by: OrderBy,
+    estimates: Estimates,
+    /// Path of the list that becomes the rows, relative to the endpoint
+    rows: Option<Vec<String>>,
+    /// Paths of the fields that are returned as JSON, relative to the endpoint
+    json_columns: Vec<Vec<String>>,
+    insert_mutation: Option<Mutation>,
+    update_mutation: Option<Mutation>,
+    delete_mutation: Option<Mutation>,
+    /// Mutation result types that are errors
+    error_types: Vec<String>,
 
     /// Values derived from the query string
     query_details: QueryDetails,
@@ -46,35 +152,207 @@ struct Config {
 
 impl Config {
     pub fn validate(&self) -> Result<()> {
-        if self.url.is_empty() {
-            return Err(Error::ModuleError("no server `url` specified".to_owned()));
-        }
+        self.transport.validate()?;
         if self.query.is_empty() {
             return Err(Error::ModuleError(
                 "no Graphql `query` specified".to_owned(),
             ));
         }
 
+        if self.pagination != Pagination::None
+            && (self.limit_arguments.limit_arg.is_some()
+                || self.limit_arguments.offset_arg.is_some())
+        {
+            return Err(Error::ModuleError(
+                "`limit_arg` and `offset_arg` can't be combined with `paginate`".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// True if the SQL LIMIT reduces the number of fetched items
+    fn handles_limit(&self) -> bool {
+        // the server limits the outer items, not the unnested rows
+        self.rows.is_none()
+            && (self.pagination != Pagination::None || self.limit_arguments.limit.is_some())
+    }
+
+    /// True if the SQL OFFSET is passed on to the server
+    fn handles_offset(&self) -> bool {
+        self.rows.is_none()
+            && (self.pagination.handles_offset() || self.limit_arguments.offset.is_some())
+    }
+
+    /// Parses the `rows` module option
+    fn set_rows(&mut self, value: &str) -> Result<()> {
+        let path: Vec<String> = value.split('.').map(|it| it.trim().to_owned()).collect();
+        if path.iter().any(|it| it.is_empty()) {
+            return Err(Error::ModuleError(format!("invalid `rows`: {value}")));
+        }
+        self.rows = Some(path);
         Ok(())
     }
+
+    /// Parses the `json_columns` module option
+    fn set_json_columns(&mut self, value: &str) -> Result<()> {
+        for column in value.split(',') {
+            let path: Vec<String> = column.split('.').map(|it| it.trim().to_owned()).collect();
+            if path.iter().any(|it| it.is_empty()) {
+                return Err(Error::ModuleError(format!(
+                    "invalid `json_columns`: {value}"
+                )));
+            }
+            self.json_columns.push(path);
+        }
+        Ok(())
+    }
+
+    /// Checks that the `rows` path leads to selected fields
+    fn resolve_rows(&self) -> Result<()> {
+        match &self.rows {
+            Some(rows)
+                if !self
+                    .query_details
+                    .results
+                    .iter()
+                    .any(|it| it.path.starts_with(rows) && it.path.len() > rows.len()) =>
+            {
+                Err(Error::ModuleError(format!(
+                    "`rows` path `{}` doesn't contain result fields",
+                    rows.join(".")
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Names of the result columns followed by the variable columns
+    fn column_names(&self) -> Vec<String> {
+        let results = self.query_details.results.iter().map(|it| it.to_string());
+        let variables = self
+            .query_details
+            .variables
+            .iter()
+            .map(|it| it.name.clone());
+        results.chain(variables).collect()
+    }
+
+    /// Primary key of a table with `update_mutation` or `delete_mutation`
+    fn primary_key(&self) -> Option<usize> {
+        if self.update_mutation.is_none() && self.delete_mutation.is_none() {
+            return None;
+        }
+        self.estimates.unique_key_cols.first().copied()
+    }
+
+    /// Resolves the mutation variables, the rows of a table with `update_mutation` or
+    /// `delete_mutation` are identified by the `unique_key`
+    fn resolve_mutations(&mut self) -> Result<()> {
+        let columns = self.column_names();
+        let mutations = [
+            &mut self.insert_mutation,
+            &mut self.update_mutation,
+            &mut self.delete_mutation,
+        ];
+        for mutation in mutations.into_iter().flatten() {
+            mutation.resolve(&columns, &self.error_types)?;
+        }
+        if (self.update_mutation.is_some() || self.delete_mutation.is_some())
+            && self.estimates.unique_key_cols.len() != 1
+        {
+            return Err(Error::ModuleError(
+                "`update_mutation` and `delete_mutation` require a single `unique_key` column"
+                    .to_owned(),
+            ));
+        }
+        // hidden variable columns can't be the PRIMARY KEY that SQLite passes for a row
+        if let Some(key) = self.primary_key() {
+            if key >= self.query_details.results.len() {
+                return Err(Error::ModuleError(format!(
+                    "the `unique_key` of `update_mutation` and `delete_mutation` must be a \
+                     result column, `{}` is a variable",
+                    columns[key]
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Names the row of a failed mutation by its `unique_key` values
+    fn row_key(&self, values: &[(&str, Value)]) -> String {
+        let key: serde_json::Map<String, Value> = self
+            .estimates
+            .unique_key
+            .iter()
+            .filter_map(|column| {
+                let (_, value) = values.iter().find(|(name, _)| name == column)?;
+                Some((column.clone(), value.clone())).filter(|_| !value.is_null())
+            })
+            .collect();
+        if key.is_empty() {
+            "a row without `unique_key`".to_owned()
+        } else {
+            Value::Object(key).to_string()
+        }
+    }
+
+    /// Fetches the server schema and resolves the result column types
+    fn introspect(&mut self) -> Result<()> {
+        let res = self.transport.post(&json!({
+          "operationName": "IntrospectionQuery",
+          "query": INTROSPECTION_QUERY,
+        }))?;
+        let schema = Schema::from_response(res.get("data").unwrap_or(&Value::Null))
+            .map_err(|err| Error::ModuleError(format!("invalid introspection result: {err}")))?;
+        schema
+            .apply(&mut self.query_details)
+            .map_err(|err| Error::ModuleError(err.to_string()))
+    }
+}
+
+/// `<param_name>=['"]?<param_value>['"]?` => `(<param_name>, <param_value>)`
+///
+/// Unlike `rusqlite::vtab::parameter` only the first `=` separates the name from the value, e.g.
+/// variable default values in the query are kept intact.
+fn parameter(c_slice: &[u8]) -> Result<(&str, &str)> {
+    let arg = str::from_utf8(c_slice)?.trim();
+    let Some((param, value)) = arg.split_once('=') else {
+        return Err(Error::ModuleError(format!("illegal argument: '{arg}'")));
+    };
+    Ok((param.trim(), dequote(value.trim())))
 }
 
 #[derive(Serialize, Deserialize)]
 struct ParameterDetail {
     /// column number of the parameter
     col: usize,
+    /// Index of the filter mapping, None if the value binds the column's variable
+    filter: Option<usize>,
+    /// All values of an IN list are passed at once
+    in_list: bool,
 }
 
 #[derive(Serialize, Deserialize)]
 struct QueryInfo {
     pub params: Vec<ParameterDetail>,
     pub col_used: u64,
+    /// Argument indices of the SQL LIMIT and OFFSET
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+    /// ORDER BY terms sorted by the server, `(column, desc)`
+    pub order_by: Vec<(usize, bool)>,
 }
 
+/// Cost of a plan that doesn't bind all required variables
+const MISSING_REQUIRED_VARIABLE_COST: f64 = 1e30;
+
 #[repr(C)]
 struct GraphQLTab {
     /// Base class. Must be first
     base: ffi::sqlite3_vtab,
+    /// Connection of the table, for the `last_insert_rowid()` that inserts keep
+    db: *mut ffi::sqlite3,
     config: Config,
 }
 
@@ -93,6 +371,7 @@ unsafe impl<'vtab> VTab<'vtab> for GraphQLTab {
 
         let mut vtab = GraphQLTab {
             base: ffi::sqlite3_vtab::default(),
+            db: unsafe { db.handle() },
             config: Config::default(),
         };
 
@@ -100,41 +379,115 @@ unsafe impl<'vtab> VTab<'vtab> for GraphQLTab {
         for c_slice in args {
             let (param, value) = parameter(c_slice)?;
             match param {
-                "url" => vtab.config.url = value.to_owned(),
                 "query" => vtab.config.query = value.to_owned(),
-                _ => {}
+                // derived from the query
+                "operationName" | "variableNames" => {}
+                "introspect" => {
+                    vtab.config.introspect = parse_boolean(value).ok_or_else(|| {
+                        Error::ModuleError(format!("invalid `introspect` value: {value}"))
+                    })?
+                }
+                "paginate" => vtab.config.pagination = Pagination::from_option(value)?,
+                "limit_arg" => vtab.config.limit_arguments.limit_arg = Some(value.to_owned()),
+                "offset_arg" => vtab.config.limit_arguments.offset_arg = Some(value.to_owned()),
+                "filter" => vtab.config.filters.push(FilterMapping::from_option(value)?),
+                "order_by_arg" => vtab.config.order_by.set_argument(value)?,
+                "order_by" => vtab.config.order_by.set_columns(value)?,
+                "estimated_rows" => vtab.config.estimates.set_estimated_rows(value)?,
+                "unique_key" => vtab.config.estimates.set_unique_key(value)?,
+                "rows" => vtab.config.set_rows(value)?,
+                "json_columns" => vtab.config.set_json_columns(value)?,
+                "insert_mutation" => {
+                    vtab.config.insert_mutation = Some(Mutation::from_option(param, value)?)
+                }
+                "update_mutation" => {
+                    vtab.config.update_mutation = Some(Mutation::from_option(param, value)?)
+                }
+                "delete_mutation" => {
+                    vtab.config.delete_mutation = Some(Mutation::from_option(param, value)?)
+                }
+                "error_types" => {
+                    vtab.config.error_types = value
+                        .split(',')
+                        .map(|it| it.trim().to_owned())
+                        .filter(|it| !it.is_empty())
+                        .collect()
+                }
+                _ => {
+                    if !vtab.config.transport.set_option(param, value)? {
+                        return Err(Error::ModuleError(format!("unknown option `{param}`")));
+                    }
+                }
             }
         }
 
         vtab.config.validate()?;
         vtab.config.query_details =
             parse(&vtab.config.query).map_err(|err| Error::ModuleError(err.to_string()))?;
+        vtab.config
+            .query_details
+            .set_json_columns(&vtab.config.json_columns)
+            .map_err(|err| Error::ModuleError(err.to_string()))?;
+        if vtab.config.introspect {
+            vtab.config.introspect()?;
+        }
+        vtab.config
+            .pagination
+            .resolve(&vtab.config.query, &vtab.config.query_details)?;
+        vtab.config
+            .limit_arguments
+            .resolve(&vtab.config.query, &vtab.config.query_details)?;
+        for (i, filter) in vtab.config.filters.iter_mut().enumerate() {
+            filter.resolve(&vtab.config.query, &vtab.config.query_details, i)?;
+        }
+        vtab.config
+            .order_by
+            .resolve(&vtab.config.query, &vtab.config.query_details)?;
+        vtab.config.estimates.resolve(&vtab.config.query_details)?;
+        vtab.config.resolve_rows()?;
+        vtab.config.resolve_mutations()?;
 
+        // Variable columns are hidden, they are left out of `SELECT *` and can be passed as
+        // arguments of a table-valued function, e.g. `SELECT * FROM orders(10)`
         let var_col_iter = vtab
             .config
             .query_details
             .variables
             .iter()
-            .map(|it| it.name.clone());
+            .map(|it| (it.name.clone(), it.var_type.column_type().sql_type(), true));
         let result_col_iter = vtab
             .config
             .query_details
             .results
             .iter()
-            .map(|it| it.to_string());
-        let cols: Vec<String> = result_col_iter.chain(var_col_iter).collect();
+            .map(|it| (it.to_string(), it.column_type.sql_type(), false));
+        let cols: Vec<(String, &str, bool)> = result_col_iter.chain(var_col_iter).collect();
 
         let mut sql = String::from("CREATE TABLE x(");
-        for (i, col) in cols.iter().enumerate() {
+        for (i, (col, col_type, hidden)) in cols.iter().enumerate() {
             sql.push('"');
             sql.push_str(col);
-            sql.push_str("\" TEXT");
-            if i == cols.len() - 1 {
-                sql.push_str(");");
-            } else {
+            sql.push('"');
+            if *hidden {
+                sql.push_str(" HIDDEN");
+            }
+            if !col_type.is_empty() {
+                sql.push(' ');
+                sql.push_str(col_type);
+            }
+            if i < cols.len() - 1 {
                 sql.push_str(", ");
             }
         }
+        // SQLite passes the primary key of updated and deleted rows
+        if let Some(key) = vtab.config.primary_key() {
+            sql.push_str(&format!(
+                ", PRIMARY KEY(\"{}\")) WITHOUT ROWID;",
+                cols[key].0
+            ));
+        } else {
+            sql.push_str(");");
+        }
 
         db.config(VTabConfig::DirectOnly)?;
         Ok((sql, vtab))
@@ -142,27 +495,194 @@ unsafe impl<'vtab> VTab<'vtab> for GraphQLTab {
 
     // Only a forward full table scan is supported.
     fn best_index(&self, info: &mut IndexInfo) -> Result<()> {
-        let query_info = QueryInfo {
-            params: info
-                .constraints()
-                .filter(|c| {
-                    c.is_usable() && c.column() as usize >= self.config.query_details.results.len()
-                })
-                .map(|c| ParameterDetail {
-                    col: c.column() as usize,
+        let results_len = self.config.query_details.results.len();
+        let mut constraint_indices = vec![];
+        let mut params = vec![];
+        let mut limit_idx = None;
+        let mut offset_idx = None;
+        // LIMIT and OFFSET can only be passed on if SQLite doesn't filter the rows afterwards
+        let mut rows_unfiltered = true;
+        let mut omitted = vec![];
+        let mut in_candidates = vec![];
+        for (i, c) in info.constraints().enumerate() {
+            match c.operator() {
+                IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_LIMIT if c.is_usable() => {
+                    limit_idx = Some(i);
+                    continue;
+                }
+                IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_OFFSET if c.is_usable() => {
+                    offset_idx = Some(i);
+                    continue;
+                }
+                _ => {}
+            }
+            // e.g. a rowid constraint
+            let Ok(col) = usize::try_from(c.column()) else {
+                rows_unfiltered = false;
+                continue;
+            };
+            let operator = Operator::from_constraint(c.operator());
+            // every filter mapping can receive a single value
+            let unused_filter = |op: Operator, params: &[ParameterDetail]| {
+                self.config.filters.iter().enumerate().position(|(f, it)| {
+                    it.column_idx == col
+                        && it.operator == op
+                        && !params.iter().any(|p| p.filter == Some(f))
                 })
-                .collect::<Vec<_>>(),
+            };
+            // IN lists are passed to `best_index` as `=` constraints
+            let filter = match operator {
+                Some(Operator::Eq) => unused_filter(Operator::Eq, &params)
+                    .or_else(|| unused_filter(Operator::In, &params)),
+                Some(operator) => unused_filter(operator, &params),
+                None => None,
+            };
+            if !c.is_usable() {
+                rows_unfiltered = false;
+            } else if let Some(filter) = filter {
+                let operator = self.config.filters[filter].operator;
+                if operator == Operator::In {
+                    in_candidates.push((params.len(), i));
+                }
+                // the server narrows the rows, SQLite re-checks comparisons and patterns on
+                // result columns as collations, case sensitivity and NULL handling of the server
+                // may differ. Variable columns have no row values to check.
+                if matches!(operator, Operator::Eq | Operator::In) || col >= results_len {
+                    omitted.push(i);
+                } else {
+                    rows_unfiltered = false;
+                }
+                constraint_indices.push(i);
+                params.push(ParameterDetail {
+                    col,
+                    filter: Some(filter),
+                    in_list: false,
+                });
+            } else if let (Some(variable), Some(Operator::Eq)) = (
+                col.checked_sub(results_len)
+                    .and_then(|i| self.config.query_details.variables.get(i)),
+                operator,
+            ) {
+                // a list variable receives all values, otherwise the endpoint is repeated
+                if variable.var_type.is_list() || self.config.pagination == Pagination::None {
+                    in_candidates.push((params.len(), i));
+                }
+                // the variable column returns the bound value, so equality always holds
+                constraint_indices.push(i);
+                params.push(ParameterDetail {
+                    col,
+                    filter: None,
+                    in_list: false,
+                });
+            } else {
+                rows_unfiltered = false;
+            }
+        }
+        for (param_idx, constraint_idx) in in_candidates {
+            // all values of the IN list are passed to a single `filter` call
+            if info.is_in_constraint(constraint_idx)?
+                && info.set_in_constraint(constraint_idx, true)?
+            {
+                params[param_idx].in_list = true;
+                if params[param_idx].filter.is_none() {
+                    // rows can't be matched to a single value of the IN list
+                    omitted.push(constraint_idx);
+                    rows_unfiltered = false;
+                }
+            }
+        }
+        // the rows of a batched IN list are only sorted per value
+        let batched = params.iter().any(|it| it.in_list && it.filter.is_none());
+        let order_by: Vec<(usize, bool)> = info
+            .order_bys()
+            .map(|it| (it.column() as usize, it.is_order_by_desc()))
+            .collect();
+        let order_by_consumed = !batched && self.config.order_by.supports(&order_by);
+        if order_by_consumed {
+            info.set_order_by_consumed(true);
+        }
+        // the LIMIT applies to the sorted rows
+        let rows_ordered = order_by.is_empty() || order_by_consumed;
+        let limit_idx =
+            limit_idx.filter(|_| rows_unfiltered && rows_ordered && self.config.handles_limit());
+        // an OFFSET always comes with a LIMIT
+        let offset_idx = offset_idx.filter(|_| limit_idx.is_some());
+        let query_info = QueryInfo {
+            limit: limit_idx.map(|_| params.len()),
+            offset: offset_idx.map(|_| params.len() + 1),
+            params,
             col_used: info.col_used(),
+            order_by: if order_by_consumed { order_by } else { vec![] },
         };
 
         info.set_idx_str(&serde_json::to_string(&query_info).unwrap());
 
         // just request use all constraints
-        for (i, _) in query_info.params.iter().enumerate() {
-            info.constraint_usage(i).set_argv_index((i + 1) as c_int);
+        for (i, constraint_idx) in constraint_indices.into_iter().enumerate() {
+            let mut usage = info.constraint_usage(constraint_idx);
+            usage.set_argv_index((i + 1) as c_int);
+            usage.set_omit(omitted.contains(&constraint_idx));
+        }
+        if let (Some(constraint_idx), Some(arg_idx)) = (limit_idx, query_info.limit) {
+            info.constraint_usage(constraint_idx)
+                .set_argv_index((arg_idx + 1) as c_int);
+        }
+        if let (Some(constraint_idx), Some(arg_idx)) = (offset_idx, query_info.offset) {
+            let mut usage = info.constraint_usage(constraint_idx);
+            usage.set_argv_index((arg_idx + 1) as c_int);
+            // SQLite skips the rows itself unless the server does
+            usage.set_omit(self.config.handles_offset());
         }
 
-        info.set_estimated_cost(1_000_000.);
+        // Make plans that don't provide all required variables unattractive, so that SQLite picks
+        // a join order that supplies them
+        let missing_required =
+            self.config
+                .query_details
+                .variables
+                .iter()
+                .enumerate()
+                .any(|(i, var)| {
+                    var.is_required()
+                        && !query_info
+                            .params
+                            .iter()
+                            .any(|p| p.col == results_len + i && p.filter.is_none())
+                });
+        let is_eq = |p: &ParameterDetail| match p.filter {
+            Some(f) => matches!(self.config.filters[f].operator, Operator::Eq | Operator::In),
+            None => true,
+        };
+        let plan = Plan {
+            eq_columns: query_info
+                .params
+                .iter()
+                .filter(|p| !p.in_list && is_eq(p))
+                .map(|p| p.col)
+                .collect(),
+            in_columns: query_info
+                .params
+                .iter()
+                .filter(|p| p.in_list)
+                .map(|p| p.col)
+                .collect(),
+            filters: query_info.params.iter().filter(|p| !is_eq(p)).count(),
+            limit: limit_idx.and_then(|idx| match info.rhs_value(idx) {
+                Ok(Some(ValueRef::Integer(limit))) if limit >= 0 => Some(limit as f64),
+                _ => None,
+            }),
+            page_size: self.config.pagination.page_size(),
+        };
+        let estimate = self.config.estimates.estimate(&plan);
+        if missing_required {
+            info.set_estimated_cost(MISSING_REQUIRED_VARIABLE_COST);
+        } else {
+            info.set_estimated_cost(estimate.cost);
+        }
+        info.set_estimated_rows(estimate.rows as i64);
+        if estimate.unique {
+            info.set_idx_flags(IndexFlags::SQLITE_INDEX_SCAN_UNIQUE);
+        }
         Ok(())
     }
 
@@ -175,6 +695,91 @@ impl CreateVTab<'_> for GraphQLTab {
     const KIND: VTabKind = VTabKind::Default;
 }
 
+impl GraphQLTab {
+    fn mutation<'a>(&self, mutation: &'a Option<Mutation>, option: &str) -> Result<&'a Mutation> {
+        mutation
+            .as_ref()
+            .ok_or_else(|| Error::ModuleError(format!("the table is read-only, no `{option}`")))
+    }
+
+    /// Column values of an inserted or updated row
+    fn row_values<'a>(
+        &self,
+        columns: &'a [String],
+        args: &Values<'_>,
+    ) -> Result<Vec<(&'a str, Value)>> {
+        columns
+            .iter()
+            .enumerate()
+            .map(|(i, column)| Ok((column.as_str(), to_json_value(args.get(i + 2)?))))
+            .collect()
+    }
+}
+
+impl UpdateVTab<'_> for GraphQLTab {
+    fn delete(&mut self, arg: ValueRef<'_>) -> Result<()> {
+        let mutation = self.mutation(&self.config.delete_mutation, "delete_mutation")?;
+        let columns = self.config.column_names();
+        let key = self.config.primary_key().ok_or_else(|| {
+            Error::ModuleError("`delete_mutation` requires a `unique_key` column".to_owned())
+        })?;
+        let values = [(columns[key].as_str(), to_json_value(arg.into()))];
+        let variables = mutation.variables(&values)?;
+        mutation.execute(
+            &self.config.transport,
+            variables,
+            &self.config.row_key(&values),
+        )?;
+        Ok(())
+    }
+
+    fn insert(&mut self, args: &Inserts<'_>) -> Result<i64> {
+        let mutation = self.mutation(&self.config.insert_mutation, "insert_mutation")?;
+        let columns = self.config.column_names();
+        let values = self.row_values(&columns, args)?;
+        let variables = mutation.variables(&values)?;
+        let result = mutation.execute(
+            &self.config.transport,
+            variables,
+            &self.config.row_key(&values),
+        )?;
+        // SQLite takes the returned rowid as `last_insert_rowid()`, it is left unchanged unless
+        // the server assigns an integer ID to a row of a rowid table
+        let last_insert_rowid = unsafe { ffi::sqlite3_last_insert_rowid(self.db) };
+        if self.config.primary_key().is_some() {
+            return Ok(last_insert_rowid);
+        }
+        let key = match self.config.estimates.unique_key_cols.as_slice() {
+            [key] => columns[*key].as_str(),
+            _ => "id",
+        };
+        let rowid = match mutation.result_value(&result, key) {
+            Some(Value::Number(id)) => id.as_i64(),
+            Some(Value::String(id)) => id.parse().ok(),
+            _ => None,
+        };
+        Ok(rowid.unwrap_or(last_insert_rowid))
+    }
+
+    fn update(&mut self, args: &Updates<'_>) -> Result<()> {
+        let mutation = self.mutation(&self.config.update_mutation, "update_mutation")?;
+        if args.get::<SqlValue>(0)? != args.get::<SqlValue>(1)? {
+            return Err(Error::ModuleError(
+                "the `unique_key` column can't be updated".to_owned(),
+            ));
+        }
+        let columns = self.config.column_names();
+        let values = self.row_values(&columns, args)?;
+        let variables = mutation.variables(&values)?;
+        mutation.execute(
+            &self.config.transport,
+            variables,
+            &self.config.row_key(&values),
+        )?;
+        Ok(())
+    }
+}
+
 #[repr(C)]
 struct GraphqlTabCursor<'vtab> {
     /// Base class. Must be first
@@ -182,7 +787,33 @@ struct GraphqlTabCursor<'vtab> {
 
     config: Config,
 
-    rows: Vec<Vec<serde_json::Value>>,
+    /// The optimized query that is sent to the server
+    query: String,
+    /// Result columns selected by the optimized query, a bit per column, the last bit stands
+    /// for all columns from 63 on
+    used_col: u64,
+    /// Variables for the next request
+    variables: serde_json::Map<String, Value>,
+    /// Values of the variable columns
+    variable_values: Vec<Value>,
+    /// Response keys of the endpoint, several if the query is batched
+    endpoints: Vec<String>,
+    /// Index of the batched variable and its value for each endpoint
+    batch: Option<(usize, Vec<Value>)>,
+    /// Endpoint of the current rows
+    root: usize,
+    /// Number of list items in the current page
+    page_items: usize,
+    /// Response of the current page, `None` once all pages have been read
+    stream: Option<ResponseStream>,
+    /// Operation result of the current page without the items of the exploded list
+    template: Value,
+    /// Path of the exploded list
+    list_path: Option<Vec<String>>,
+    /// Decoded rows that haven't been consumed yet, the first one is the current row
+    rows: VecDeque<Vec<Value>>,
+    /// Number of list items that are still needed to satisfy the SQL LIMIT
+    remaining: Option<usize>,
     row_number: usize,
     phantom: PhantomData<&'vtab GraphQLTab>,
 }
@@ -194,162 +825,333 @@ impl GraphqlTabCursor<'_> {
 
             config,
 
-            rows: vec![],
+            query: String::new(),
+            used_col: 0,
+            variables: serde_json::Map::new(),
+            variable_values: vec![],
+            endpoints: vec![],
+            batch: None,
+            root: 0,
+            page_items: 0,
+            stream: None,
+            template: Value::Null,
+            list_path: None,
+            rows: VecDeque::new(),
+            remaining: None,
             row_number: 0,
             phantom: PhantomData,
         }
     }
+
+    /// Sends the request for the next page, the response is decoded while rows are consumed
+    fn send_request(&mut self) -> Result<()> {
+        let response = self.config.transport.send(&json!({
+          "operationName": self.config.query_details.operation_name,
+          "query": self.query,
+          "variables": self.variables
+        }))?;
+        // fields that were removed from the query would keep the template from completing
+        let result_paths = self
+            .config
+            .query_details
+            .results
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| (self.used_col >> (*i).min(63)) & 1 != 0)
+            .map(|(_, it)| it.path.clone())
+            .collect();
+        self.stream = Some(ResponseStream::new(
+            response,
+            self.endpoints.clone(),
+            result_paths,
+            self.config.rows.clone(),
+        ));
+        self.template = Value::Null;
+        self.list_path = None;
+        self.page_items = 0;
+        Ok(())
+    }
+
+    /// Decodes the response until a row is available or all pages have been read
+    fn fill_rows(&mut self) -> Result<()> {
+        while self.rows.is_empty() {
+            let Some(stream) = &self.stream else {
+                return Ok(());
+            };
+            match stream.next_event() {
+                Event::Template {
+                    root,
+                    template,
+                    list_path,
+                } => {
+                    self.root = root;
+                    self.template = template;
+                    self.list_path = Some(list_path);
+                }
+                Event::Item(item) => {
+                    for levels in self.unnest(&item) {
+                        let row = self.row(&levels);
+                        self.rows.push_back(row);
+                    }
+                }
+                Event::End {
+                    root,
+                    result,
+                    list_path,
+                    item_count,
+                } => {
+                    self.root = root;
+                    self.template = result;
+                    // Without a list the operation result is a single row
+                    if list_path.is_none() {
+                        self.list_path = None;
+                        let row = self.row(&[]);
+                        self.rows.push_back(row);
+                    }
+                    self.page_items += item_count;
+                }
+                Event::Done => {
+                    self.remaining = self
+                        .remaining
+                        .map(|remaining| remaining.saturating_sub(self.page_items));
+                    if self.remaining != Some(0)
+                        && self.config.pagination.next_page(
+                            &self.template,
+                            self.page_items,
+                            &mut self.variables,
+                        )
+                    {
+                        self.send_request()?;
+                    } else {
+                        self.stream = None;
+                    }
+                }
+                Event::Error(err) => {
+                    self.stream = None;
+                    return Err(Error::ModuleError(err));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Unnests the lists on the `rows` path below an item of the exploded list. Returns the
+    /// levels of each row, i.e. the list items and the length of their path, a missing inner
+    /// list returns no rows.
+    fn unnest<'a>(&self, item: &'a Value) -> Vec<Vec<(usize, &'a Value)>> {
+        let list_path = self.list_path.as_deref().unwrap_or_default();
+        let mut rows = vec![(vec![(list_path.len(), item)], item)];
+        let Some(rows_path) = self
+            .config
+            .rows
+            .as_ref()
+            .filter(|it| it.starts_with(list_path))
+        else {
+            return vec![vec![(list_path.len(), item)]];
+        };
+        for (depth, segment) in rows_path.iter().enumerate().skip(list_path.len()) {
+            rows = rows
+                .into_iter()
+                .flat_map(|(levels, value)| match value.get(segment) {
+                    Some(Value::Array(items)) => items
+                        .iter()
+                        .map(|item| {
+                            let mut levels = levels.clone();
+                            levels.push((depth + 1, item));
+                            (levels, item)
+                        })
+                        .collect(),
+                    Some(Value::Null) | None => vec![],
+                    Some(value) => vec![(levels, value)],
+                })
+                .collect();
+        }
+        rows.into_iter().map(|(levels, _)| levels).collect()
+    }
+
+    /// Builds a row from the levels of the exploded list, the values outside the list and the
+    /// variable values
+    fn row(&self, levels: &[(usize, &Value)]) -> Vec<Value> {
+        let rows_path = self.config.rows.as_deref().or(self.list_path.as_deref());
+        let results = self.config.query_details.results.iter().map(|result| {
+            // the innermost level that contains the result
+            let level = levels.iter().rev().find(|(len, _)| {
+                rows_path
+                    .and_then(|it| it.get(..*len))
+                    .is_some_and(|it| result.path.starts_with(it))
+            });
+            let (mut value, path) = match level {
+                Some((len, item)) => (*item, &result.path[*len..]),
+                None => (&self.template, &result.path[..]),
+            };
+            for segment in path {
+                value = value.get(segment).unwrap_or(&Value::Null);
+            }
+            value.clone()
+        });
+        let variables =
+            self.variable_values
+                .iter()
+                .enumerate()
+                .map(|(i, value)| match &self.batch {
+                    Some((batched, values)) if *batched == i => {
+                        values.get(self.root).cloned().unwrap_or(Value::Null)
+                    }
+                    _ => value.clone(),
+                });
+        results.chain(variables).collect()
+    }
 }
 
 unsafe impl VTabCursor for GraphqlTabCursor<'_> {
-    // Only a full table scan is supported.  So `filter` simply rewinds to
-    // the beginning.
-    fn filter(&mut self, _idx_num: c_int, idx_str: Option<&str>, args: &Values<'_>) -> Result<()> {
+    fn filter(&mut self, _idx_num: c_int, idx_str: Option<&str>, args: &Filters<'_>) -> Result<()> {
         self.rows.clear();
         self.row_number = 0;
+        self.stream = None;
+        self.batch = None;
+        self.endpoints = vec![self.config.query_details.endpoint_name.clone()];
 
         let query_info = idx_str
             .map(|s| serde_json::from_str::<QueryInfo>(s).unwrap())
             .unwrap();
+        let results_len = self.config.query_details.results.len();
         let mut variables = serde_json::Map::new();
+        // Values of the variable columns
+        let mut column_values: Vec<Option<Value>> =
+            vec![None; self.config.query_details.variables.len()];
+        let mut injected = self.config.pagination.injected_arguments().to_vec();
         for (i, param) in query_info.params.iter().enumerate() {
-            let Some(config_param) = self
-                .config
-                .query_details
-                .variables
-                .get(param.col - self.config.query_details.results.len())
-            else {
+            if let Some(filter) = param.filter {
+                let filter = &self.config.filters[filter];
+                let value = if param.in_list {
+                    Value::Array(in_values(args, i)?)
+                } else {
+                    to_json_value(args.get(i)?)
+                };
+                let value = filter.value_type().coerce(value).map_err(|err| {
+                    Error::ModuleError(format!("filter on `{}`: {err}", filter.column))
+                })?;
+                variables.insert(filter.variable.clone(), value);
+                injected.extend(filter.injected.clone());
+                continue;
+            }
+            let var_idx = param.col - results_len;
+            let Some(config_param) = self.config.query_details.variables.get(var_idx) else {
                 continue;
             };
-            variables.insert(
-                config_param.name.clone(),
-                serde_json::Value::String(args.get(i)?),
-            );
-        }
-
-        let client = reqwest::blocking::Client::new();
-        let query = optimize_query(&self.config.query, query_info.col_used)
-            .map_err(|err| Error::ModuleError(err.to_string()))?;
-        println!("API Request:\n{query}");
-        let res = client
-            .post(&self.config.url)
-            .json(&json!({
-              "operationName": self.config.query_details.operation_name,
-              "query": query,
-              "variables": variables
-            }))
-            .send()
-            .map_err(|err| Error::ModuleError(err.to_string()))?
-            .json::<serde_json::Value>()
-            .map_err(|err| Error::ModuleError(err.to_string()))?;
-
-        if let Some(errors) = res.get("errors") {
-            return Err(Error::ModuleError(errors.to_string()));
-        };
-
-        let operation_result = res
-            .get("data")
-            .unwrap_or(&Value::Null)
-            .get(&self.config.query_details.endpoint_name)
-            .unwrap_or(&Value::Null);
-
-        // find first array
-        let mut found_array: Option<ResultPath> = None;
-        let mut array_value: Option<&Value> = None;
-        for expected_result in &self.config.query_details.results {
-            let mut value = operation_result;
-            let mut current = vec![];
-            for path in &expected_result.path {
-                current.push(path.clone());
-                value = value.get(&path).unwrap_or(&Value::Null);
-                if !value.is_array() {
-                    continue;
-                }
-                found_array = Some(ResultPath { path: current });
-                array_value = Some(value);
-                break;
+            let coerce = |value| {
+                config_param.var_type.coerce(value).map_err(|err| {
+                    Error::ModuleError(format!("variable `{}`: {err}", config_param.name))
+                })
+            };
+            if !param.in_list {
+                let value = to_json_value(args.get(i)?);
+                variables.insert(config_param.name.clone(), coerce(value.clone())?);
+                column_values[var_idx] = Some(value);
+                continue;
             }
-            if found_array.is_some() {
-                break;
+            let values = in_values(args, i)?;
+            if config_param.var_type.is_list() {
+                variables.insert(
+                    config_param.name.clone(),
+                    coerce(Value::Array(values.clone()))?,
+                );
+                column_values[var_idx] = Some(Value::Array(values));
+            } else {
+                for (k, value) in values.iter().enumerate() {
+                    variables.insert(batch_variable(k), coerce(value.clone())?);
+                }
+                self.batch = Some((var_idx, values));
             }
         }
-        // build template row with none array elements
-        let mut template_row = vec![];
-        for expected_result in &self.config.query_details.results {
-            if let Some(found_array) = &found_array {
-                if expected_result.to_string() == found_array.to_string() {
-                    template_row.push(Value::Null);
-                    continue;
-                }
+        for (i, variable) in self.config.query_details.variables.iter().enumerate() {
+            let batched = matches!(&self.batch, Some((batched, _)) if *batched == i);
+            if batched || variables.contains_key(&variable.name) {
+                continue;
             }
-            let mut value = operation_result;
-            for path in &expected_result.path {
-                value = value.get(&path).unwrap_or(&Value::Null);
+            if let Some(default_value) = &variable.default_value {
+                variables.insert(variable.name.clone(), default_value.clone());
+            } else if variable.is_required() {
+                return Err(Error::ModuleError(format!(
+                    "missing value for required variable `{}`",
+                    variable.name
+                )));
             }
-            template_row.push(value.clone());
         }
 
-        if found_array.is_none() {
-            self.rows.push(template_row);
-        } else {
-            let array = array_value.unwrap().as_array().unwrap();
-            let found_array = found_array.unwrap();
-            let found_array_str = found_array.to_string();
-            for (i, array_element) in array.iter().enumerate() {
-                let mut row = vec![];
-                for expected_result in &self.config.query_details.results {
-                    if expected_result.to_string().starts_with(&found_array_str) {
-                        let mut value = array_element;
-                        for path in expected_result.path.iter().skip(found_array.path.len()) {
-                            value = value.get(&path).unwrap_or(&Value::Null);
-                        }
-                        row.push(value.clone());
-                    } else {
-                        row.push(template_row[i].clone());
-                    }
-                }
-                self.rows.push(row);
-            }
-        }
+        self.variable_values = column_values
+            .into_iter()
+            .zip(&self.config.query_details.variables)
+            .map(|(value, variable)| {
+                value.unwrap_or_else(|| variable.default_value.clone().unwrap_or(Value::Null))
+            })
+            .collect();
 
-        // Fill in the query parameters
-        for row in self.rows.iter_mut() {
-            for (i, _) in self.config.query_details.variables.iter().enumerate() {
-                let Some(parameter_idx) = query_info
-                    .params
-                    .iter()
-                    .position(|d| d.col == i + self.config.query_details.results.len())
-                else {
-                    row.push(Value::Null);
-                    continue;
-                };
-                row.push(serde_json::Value::String(args.get(parameter_idx)?));
+        // a negative LIMIT means no limit
+        let limit = match query_info.limit {
+            Some(i) => Some(args.get::<i64>(i)?).filter(|it| *it >= 0),
+            None => None,
+        };
+        let skip = match query_info.offset {
+            Some(i) => Some(args.get::<i64>(i)?.max(0)),
+            None => None,
+        };
+        // rows skipped by SQLite need to be fetched as well
+        let unhandled_skip = skip.filter(|_| !self.config.handles_offset());
+        let needed = limit.map(|limit| limit + unhandled_skip.unwrap_or(0));
+        self.remaining = needed.map(|it| it as usize);
+
+        self.config
+            .limit_arguments
+            .apply(&mut variables, limit, skip, &mut injected);
+        injected.extend(self.config.order_by.injected_argument(&query_info.order_by));
+        self.used_col = query_info.col_used | self.config.pagination.required_columns();
+        self.query = optimize_query(
+            &self.config.query,
+            self.used_col,
+            &self.config.json_columns,
+            &injected,
+        )
+        .map_err(|err| Error::ModuleError(err.to_string()))?;
+        if let Some((var_idx, values)) = &self.batch {
+            // an empty IN list matches nothing
+            if values.is_empty() {
+                return Ok(());
             }
+            let variable = &self.config.query_details.variables[*var_idx].name;
+            self.query = batch_query(&self.query, variable, values.len())
+                .map_err(|err| Error::ModuleError(err.to_string()))?;
+            self.endpoints = (0..values.len()).map(batch_alias).collect();
         }
-
-        Ok(())
+        self.config
+            .pagination
+            .first_page(&mut variables, needed, skip);
+        self.variables = variables;
+        self.send_request()?;
+        self.fill_rows()
     }
 
     fn next(&mut self) -> Result<()> {
         self.row_number += 1;
-        Ok(())
+        self.rows.pop_front();
+        // Rows and pages are only decoded and fetched while SQLite keeps stepping
+        self.fill_rows()
     }
 
     fn eof(&self) -> bool {
-        self.row_number >= self.rows.len()
+        self.rows.is_empty()
     }
 
     fn column(&self, ctx: &mut Context, col: c_int) -> Result<()> {
-        let columns = &self.rows[self.row_number];
-        if col < 0 || col as usize >= columns.len() {
+        let Some(columns) = self.rows.front() else {
+            return Err(Error::ModuleError("no current row".to_owned()));
+        };
+        let Some(value) = columns.get(col as usize) else {
             return Err(Error::ModuleError(format!(
                 "column index out of bounds: {col}"
             )));
-        }
-        if columns.is_empty() {
-            return ctx.set_result(&Null);
-        }
-        ctx.set_result(&columns[col as usize].as_str().unwrap_or("").to_owned())
+        };
+        ctx.set_result(&to_sql_value(value))
     }
 
     fn rowid(&self) -> Result<i64> {
@@ -357,11 +1159,105 @@ unsafe impl VTabCursor for GraphqlTabCursor<'_> {
     }
 }
 
+/// Converts a JSON value from the response to the matching SQLite value
+pub fn to_sql_value(value: &Value) -> SqlValue {
+    match value {
+        Value::Null => SqlValue::Null,
+        Value::Bool(value) => SqlValue::Integer(*value as i64),
+        Value::Number(number) => match number.as_i64() {
+            Some(value) => SqlValue::Integer(value),
+            None => SqlValue::Real(number.as_f64().unwrap_or(f64::NAN)),
+        },
+        Value::String(value) => SqlValue::Text(value.clone()),
+        // Lists and objects are returned as serialized JSON
+        Value::Array(_) | Value::Object(_) => SqlValue::Text(value.to_string()),
+    }
+}
+
+/// Returns the values of an IN constraint that is passed to `filter` at once
+fn in_values(args: &Filters<'_>, i: usize) -> Result<Vec<Value>> {
+    args.in_values(i)?
+        .map(|value| Ok(to_json_value(value.into())))
+        .collect()
+}
+
+/// Converts a SQLite value to the matching JSON value
+fn to_json_value(value: SqlValue) -> Value {
+    match value {
+        SqlValue::Null => Value::Null,
+        SqlValue::Integer(value) => Value::from(value),
+        SqlValue::Real(value) => Value::from(value),
+        SqlValue::Text(value) => Value::String(value),
+        SqlValue::Blob(value) => Value::String(String::from_utf8_lossy(&value).into_owned()),
+    }
+}
+
 #[cfg(test)]
 mod test {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
     use crate::graphql;
     use fallible_iterator::FallibleIterator;
-    use rusqlite::{Connection, Result};
+    use rusqlite::{types::Value as SqlValue, Connection, Result};
+    use serde_json::{json, Value};
+
+    /// Starts a local GraphQL server answering requests with `handler`.
+    /// Returns the server url and the list of received request bodies.
+    fn mock_server(
+        handler: impl Fn(&Value) -> Value + Send + Sync + 'static,
+    ) -> (String, Arc<Mutex<Vec<Value>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/graphql", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(vec![]));
+        let received = requests.clone();
+        let handler = Arc::new(handler);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else {
+                    return;
+                };
+                let received = received.clone();
+                let handler = handler.clone();
+                // connections are kept alive, several can be open at once
+                std::thread::spawn(move || {
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    loop {
+                        let mut content_length = 0;
+                        let mut line = String::new();
+                        if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                            break;
+                        }
+                        loop {
+                            line.clear();
+                            reader.read_line(&mut line).unwrap();
+                            if line.trim().is_empty() {
+                                break;
+                            }
+                            let lower = line.to_lowercase();
+                            if let Some(value) = lower.strip_prefix("content-length:") {
+                                content_length = value.trim().parse().unwrap();
+                            }
+                        }
+                        let mut body = vec![0; content_length];
+                        reader.read_exact(&mut body).unwrap();
+                        let request: Value = serde_json::from_slice(&body).unwrap();
+                        let response = handler(&request).to_string();
+                        received.lock().unwrap().push(request);
+                        write!(
+                            stream,
+                            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                            response.len(),
+                            response
+                        )
+                        .unwrap();
+                    }
+                });
+            }
+        });
+        (url, requests)
+    }
 
     #[test]
     fn test_graphql_module() -> Result<()> {
@@ -428,4 +1324,938 @@ mod test {
         db.execute_batch("DROP TABLE films")?;
         Ok(())
     }
+
+    #[test]
+    fn test_relay_pagination() -> Result<()> {
+        let (url, requests) = mock_server(|request| {
+            let after = request["variables"]["_apisql_after"].as_str();
+            let (ids, has_next_page) = match after {
+                None => (vec![1, 2], true),
+                Some("c2") => (vec![3, 4], true),
+                _ => (vec![5], false),
+            };
+            let edges: Vec<Value> = ids.iter().map(|id| json!({"node": {"id": id}})).collect();
+            let end_cursor = format!("c{}", ids.last().unwrap());
+            json!({"data": {"orders": {
+                "edges": edges,
+                "pageInfo": {"hasNextPage": has_next_page, "endCursor": end_cursor}
+            }}})
+        });
+        let db = Connection::open_in_memory()?;
+        graphql::load_module(&db)?;
+        db.execute_batch(&format!(
+            "CREATE VIRTUAL TABLE orders USING graphql(url='{url}', paginate='relay(page_size=2)',
+                query='query Orders {{
+                  orders {{ edges {{ node {{ id }} }} pageInfo {{ hasNextPage endCursor }} }}
+                }}')"
+        ))?;
+
+        let mut s = db.prepare("SELECT edges_node_id FROM orders")?;
+        let ids: Vec<i64> = s.query([])?.map(|row| row.get(0)).collect()?;
+        assert_eq!(ids, vec![1, 2, 3, 4, 5]);
+        assert_eq!(requests.lock().unwrap().len(), 3);
+        assert_eq!(requests.lock().unwrap()[0]["variables"]["_apisql_first"], 2);
+
+        // pages are only fetched while SQLite keeps stepping
+        requests.lock().unwrap().clear();
+        let mut s = db.prepare("SELECT edges_node_id FROM orders LIMIT 2")?;
+        let ids: Vec<i64> = s.query([])?.map(|row| row.get(0)).collect()?;
+        assert_eq!(ids, vec![1, 2]);
+        assert_eq!(requests.lock().unwrap().len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_limit_pushdown() -> Result<()> {
+        let (url, requests) = mock_server(|request| {
+            let offset = request["variables"]["_apisql_offset"].as_i64().unwrap_or(0);
+            let first = request["variables"]["first"].as_i64().unwrap_or(10);
+            let nodes: Vec<Value> = (offset..offset + first)
+                .map(|id| json!({"id": id}))
+                .collect();
+            json!({"data": {"orders": {"nodes": nodes}}})
+        });
+        let db = Connection::open_in_memory()?;
+        graphql::load_module(&db)?;
+        db.execute_batch(&format!(
+            "CREATE VIRTUAL TABLE orders USING graphql(url='{url}', limit_arg=first,
+                offset_arg=offset, query='query Orders($first: Int) {{
+                  orders(first: $first) {{ nodes {{ id }} }}
+                }}')"
+        ))?;
+
+        let mut s = db.prepare("SELECT nodes_id FROM orders LIMIT 3 OFFSET 2")?;
+        let ids: Vec<i64> = s.query([])?.map(|row| row.get(0)).collect()?;
+        assert_eq!(ids, vec![2, 3, 4]);
+        let request = requests.lock().unwrap().pop().unwrap();
+        assert_eq!(
+            request["variables"],
+            json!({"first": 3, "_apisql_offset": 2})
+        );
+        assert!(request["query"]
+            .as_str()
+            .unwrap()
+            .contains("orders(first: $first, offset: $_apisql_offset)"));
+
+        // SQLite filters the rows, the limit can't be passed on
+        let mut s = db.prepare("SELECT nodes_id FROM orders WHERE nodes_id > 5 LIMIT 2")?;
+        let ids: Vec<i64> = s.query([])?.map(|row| row.get(0)).collect()?;
+        assert_eq!(ids, vec![6, 7]);
+        let request = requests.lock().unwrap().pop().unwrap();
+        assert_eq!(request["variables"], json!({}));
+        Ok(())
+    }
+
+    #[test]
+    fn test_limit_stops_pagination() -> Result<()> {
+        let (url, requests) = mock_server(|request| {
+            let offset = request["variables"]["_apisql_offset"].as_i64().unwrap();
+            let limit = request["variables"]["_apisql_limit"].as_i64().unwrap();
+            let nodes: Vec<Value> = (offset..offset + limit)
+                .map(|id| json!({"id": id}))
+                .collect();
+            json!({"data": {"orders": {"nodes": nodes}}})
+        });
+        let db = Connection::open_in_memory()?;
+        graphql::load_module(&db)?;
+        db.execute_batch(&format!(
+            "CREATE VIRTUAL TABLE orders USING graphql(url='{url}',
+                paginate='offset(page_size=2, offset_var=offset, limit_var=limit)',
+                query='query Orders {{ orders {{ nodes {{ id }} }} }}')"
+        ))?;
+
+        let mut s = db.prepare("SELECT nodes_id FROM orders LIMIT 3 OFFSET 1")?;
+        let ids: Vec<i64> = s.query([])?.map(|row| row.get(0)).collect()?;
+        assert_eq!(ids, vec![1, 2, 3]);
+        let requests = requests.lock().unwrap();
+        let variables: Vec<&Value> = requests.iter().map(|it| &it["variables"]).collect();
+        assert_eq!(
+            variables,
+            vec![
+                &json!({"_apisql_offset": 1, "_apisql_limit": 2}),
+                &json!({"_apisql_offset": 3, "_apisql_limit": 2}),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_filter_pushdown() -> Result<()> {
+        let (url, requests) = mock_server(|_| {
+            json!({"data": {"orders": [
+                {"id": 1, "createdAt": "2024-02-01"},
+                {"id": 2, "createdAt": "2024-03-01"}
+            ]}})
+        });
+        let db = Connection::open_in_memory()?;
+        graphql::load_module(&db)?;
+        db.execute_batch(&format!(
+            "CREATE VIRTUAL TABLE orders USING graphql(url='{url}',
+                filter='created_at > filter.createdAt.after',
+                filter='createdAt > filter.createdAfter: String',
+                query='query Orders($created_at: String) {{
+                  orders(filter: {{createdAt: {{equalTo: $created_at}}}}) {{ id createdAt }}
+                }}')"
+        ))?;
+
+        let mut s = db.prepare("SELECT id FROM orders WHERE created_at > '2024-01-01'")?;
+        let ids: Vec<i64> = s.query([])?.map(|row| row.get(0)).collect()?;
+        assert_eq!(ids, vec![1, 2]);
+        let request = requests.lock().unwrap().pop().unwrap();
+        assert_eq!(
+            request["variables"],
+            json!({"_apisql_filter_0": "2024-01-01"})
+        );
+        assert!(request["query"]
+            .as_str()
+            .unwrap()
+            .contains("filter: {createdAt: {after: $_apisql_filter_0, equalTo: $created_at}}"));
+
+        // SQLite re-checks comparisons on result columns, the server may compare differently
+        let mut s = db.prepare("SELECT id FROM orders WHERE createdAt > '2024-02-15'")?;
+        let ids: Vec<i64> = s.query([])?.map(|row| row.get(0)).collect()?;
+        assert_eq!(ids, vec![2]);
+        let request = requests.lock().unwrap().pop().unwrap();
+        assert_eq!(
+            request["variables"],
+            json!({"_apisql_filter_1": "2024-02-15"})
+        );
+
+        // unmapped operators are evaluated by SQLite instead of binding the variable
+        let mut s = db.prepare("SELECT id FROM orders WHERE created_at < '2024-01-01'")?;
+        let ids: Vec<i64> = s.query([])?.map(|row| row.get(0)).collect()?;
+        assert!(ids.is_empty());
+        let request = requests.lock().unwrap().pop().unwrap();
+        assert_eq!(request["variables"], json!({}));
+        Ok(())
+    }
+
+    #[test]
+    fn test_result_column_filter() -> Result<()> {
+        let (url, requests) = mock_server(|_| {
+            json!({"data": {"allFilms": {"films": [
+                {"title": "A New Hope", "director": "George Lucas"}
+            ]}}})
+        });
+        let db = Connection::open_in_memory()?;
+        graphql::load_module(&db)?;
+        db.execute_batch(&format!(
+            "CREATE VIRTUAL TABLE films USING graphql(url='{url}',
+                filter='films_director = films(filter.director): String',
+                query='query Films {{ allFilms {{ films {{ title director }} }} }}')"
+        ))?;
+
+        let mut s =
+            db.prepare("SELECT films_title FROM films WHERE films_director = 'George Lucas'")?;
+        let titles: Vec<String> = s.query([])?.map(|row| row.get(0)).collect()?;
+        assert_eq!(titles, vec!["A New Hope".to_owned()]);
+        let request = requests.lock().unwrap().pop().unwrap();
+        assert_eq!(
+            request["variables"],
+            json!({"_apisql_filter_0": "George Lucas"})
+        );
+        assert!(request["query"]
+            .as_str()
+            .unwrap()
+            .contains("query Films($_apisql_filter_0: String)"));
+        assert!(request["query"]
+            .as_str()
+            .unwrap()
+            .contains("films(filter: {director: $_apisql_filter_0})"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_in_filter() -> Result<()> {
+        let (url, requests) = mock_server(|request| {
+            let statuses = request["variables"]["_apisql_filter_0"].as_array().unwrap();
+            let orders: Vec<Value> = statuses
+                .iter()
+                .enumerate()
+                .map(|(i, status)| json!({"id": i + 1, "status": status}))
+                .collect();
+            json!({"data": {"orders": orders}})
+        });
+        let db = Connection::open_in_memory()?;
+        graphql::load_module(&db)?;
+        db.execute_batch(&format!(
+            "CREATE VIRTUAL TABLE orders USING graphql(url='{url}',
+                filter='status IN filter.statuses: String',
+                query='query Orders {{ orders {{ id status }} }}')"
+        ))?;
+
+        let mut s = db.prepare("SELECT id FROM orders WHERE status IN ('OPEN', 'PAID')")?;
+        let ids: Vec<i64> = s.query([])?.map(|row| row.get(0)).collect()?;
+        assert_eq!(ids, vec![1, 2]);
+        let request = requests.lock().unwrap().pop().unwrap();
+        assert!(requests.lock().unwrap().is_empty());
+        assert_eq!(
+            request["variables"],
+            json!({"_apisql_filter_0": ["OPEN", "PAID"]})
+        );
+        assert!(request["query"]
+            .as_str()
+            .unwrap()
+            .contains("orders(filter: {statuses: $_apisql_filter_0})"));
+
+        // a single value is passed as a list of one
+        let mut s = db.prepare("SELECT id FROM orders WHERE status = 'OPEN'")?;
+        let ids: Vec<i64> = s.query([])?.map(|row| row.get(0)).collect()?;
+        assert_eq!(ids, vec![1]);
+        let request = requests.lock().unwrap().pop().unwrap();
+        assert_eq!(request["variables"], json!({"_apisql_filter_0": ["OPEN"]}));
+        Ok(())
+    }
+
+    #[test]
+    fn test_rowid_constraint() -> Result<()> {
+        let (url, _) = mock_server(|_| json!({"data": {"orders": [{"id": 1}, {"id": 2}]}}));
+        let db = Connection::open_in_memory()?;
+        graphql::load_module(&db)?;
+        db.execute_batch(&format!(
+            "CREATE VIRTUAL TABLE orders USING graphql(url='{url}',
+                query='query Orders {{ orders {{ id }} }}')"
+        ))?;
+        // SQLite evaluates the constraint, rowids count the rows from 0
+        let mut s = db.prepare("SELECT id FROM orders WHERE rowid = 1")?;
+        let ids: Vec<i64> = s.query([])?.map(|row| row.get(0)).collect()?;
+        assert_eq!(ids, vec![2]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_in_list() -> Result<()> {
+        let (url, requests) = mock_server(|request| {
+            let variables = request["variables"].as_object().unwrap();
+            if let Some(ids) = variables.get("ids") {
+                let nodes: Vec<Value> = ids
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|id| json!({"id": id, "name": format!("user {}", id.as_str().unwrap())}))
+                    .collect();
+                return json!({"data": {"users": nodes}});
+            }
+            // batched query, one aliased endpoint per value
+            let data: serde_json::Map<String, Value> = variables
+                .iter()
+                .map(|(name, id)| {
+                    let alias = name.replace("_apisql_in_", "_apisql_");
+                    let name = format!("user {}", id.as_str().unwrap());
+                    (alias, json!({"id": id, "name": name}))
+                })
+                .collect();
+            json!({"data": data})
+        });
+        let db = Connection::open_in_memory()?;
+        graphql::load_module(&db)?;
+        db.execute_batch(&format!(
+            "CREATE VIRTUAL TABLE user USING graphql(url='{url}',
+                query='query User($user_id: ID!) {{ user(id: $user_id) {{ id name }} }}');
+             CREATE VIRTUAL TABLE users USING graphql(url='{url}',
+                query='query Users($ids: [ID!]) {{ users(ids: $ids) {{ id name }} }}');"
+        ))?;
+
+        let mut s =
+            db.prepare("SELECT user_id, name FROM user WHERE user_id IN ('1', '2', '3')")?;
+        let rows: Vec<(String, String)> = s
+            .query([])?
+            .map(|row| Ok((row.get(0)?, row.get(1)?)))
+            .collect()?;
+        assert_eq!(
+            rows,
+            vec![
+                ("1".to_owned(), "user 1".to_owned()),
+                ("2".to_owned(), "user 2".to_owned()),
+                ("3".to_owned(), "user 3".to_owned()),
+            ]
+        );
+        let request = requests.lock().unwrap().pop().unwrap();
+        assert!(requests.lock().unwrap().is_empty());
+        let query = request["query"].as_str().unwrap();
+        assert!(query.contains("query User($_apisql_in_0: ID!, $_apisql_in_1: ID!"));
+        assert!(query.contains("_apisql_2: user(id: $_apisql_in_2)"));
+
+        let mut s = db.prepare("SELECT name FROM users WHERE ids IN ('4', '5')")?;
+        let names: Vec<String> = s.query([])?.map(|row| row.get(0)).collect()?;
+        assert_eq!(names, vec!["user 4".to_owned(), "user 5".to_owned()]);
+        let request = requests.lock().unwrap().pop().unwrap();
+        assert!(requests.lock().unwrap().is_empty());
+        assert_eq!(request["variables"], json!({"ids": ["4", "5"]}));
+        Ok(())
+    }
+
+    #[test]
+    fn test_order_by_pushdown() -> Result<()> {
+        let (url, requests) = mock_server(|request| {
+            let first = request["variables"]["_apisql_limit"].as_i64().unwrap_or(3) as usize;
+            // already sorted by the server
+            let nodes: Vec<Value> = [
+                ("b", "2024-03-01"),
+                ("c", "2024-02-01"),
+                ("a", "2024-01-01"),
+            ]
+            .iter()
+            .take(first)
+            .map(|(id, created_at)| json!({"id": id, "createdAt": created_at}))
+            .collect();
+            json!({"data": {"orders": {"nodes": nodes}}})
+        });
+        let db = Connection::open_in_memory()?;
+        graphql::load_module(&db)?;
+        db.execute_batch(&format!(
+            "CREATE VIRTUAL TABLE orders USING graphql(url='{url}', limit_arg=first,
+                order_by_arg=sort, order_by='nodes_createdAt={{key: createdAt, desc: $desc}}',
+                query='query Orders {{ orders {{ nodes {{ id createdAt }} }} }}')"
+        ))?;
+
+        let mut s =
+            db.prepare("SELECT nodes_id FROM orders ORDER BY nodes_createdAt DESC LIMIT 2")?;
+        let ids: Vec<String> = s.query([])?.map(|row| row.get(0)).collect()?;
+        assert_eq!(ids, vec!["b".to_owned(), "c".to_owned()]);
+        let request = requests.lock().unwrap().pop().unwrap();
+        assert_eq!(request["variables"], json!({"_apisql_limit": 2}));
+        assert!(request["query"]
+            .as_str()
+            .unwrap()
+            .contains("orders(first: $_apisql_limit, sort: {desc: true, key: createdAt})"));
+
+        // SQLite sorts by columns without a sort value, the limit can't be passed on
+        let mut s = db.prepare("SELECT nodes_id FROM orders ORDER BY nodes_id LIMIT 2")?;
+        let ids: Vec<String> = s.query([])?.map(|row| row.get(0)).collect()?;
+        assert_eq!(ids, vec!["a".to_owned(), "b".to_owned()]);
+        let request = requests.lock().unwrap().pop().unwrap();
+        assert_eq!(request["variables"], json!({}));
+        Ok(())
+    }
+
+    #[test]
+    fn test_table_valued_function() -> Result<()> {
+        let (url, requests) = mock_server(|request| {
+            let variables = &request["variables"];
+            let token = format!(
+                "{}:{}",
+                variables["username"].as_str().unwrap(),
+                variables["password"].as_str().unwrap()
+            );
+            json!({"data": {"login": {"token": token}}})
+        });
+        let db = Connection::open_in_memory()?;
+        graphql::load_module(&db)?;
+        db.execute_batch(&format!(
+            "CREATE VIRTUAL TABLE auth_token USING graphql(url='{url}',
+                query='query Login($username: String!, $password: String!) {{
+                  login(username: $username, password: $password) {{ token }}
+                }}')"
+        ))?;
+
+        let mut s = db.prepare("SELECT * FROM auth_token('user', 'pw')")?;
+        assert_eq!(s.column_names(), vec!["token"]);
+        let token: String = s.query_row([], |row| row.get(0))?;
+        assert_eq!(token, "user:pw");
+        let request = requests.lock().unwrap().pop().unwrap();
+        assert_eq!(
+            request["variables"],
+            json!({"username": "user", "password": "pw"})
+        );
+
+        let token: String = db.query_row(
+            "SELECT token FROM auth_token WHERE username = 'admin' AND password = 'secret'",
+            [],
+            |row| row.get(0),
+        )?;
+        assert_eq!(token, "admin:secret");
+        Ok(())
+    }
+
+    #[test]
+    fn test_query_function() -> Result<()> {
+        let (url, requests) = mock_server(|request| {
+            let episode = request["variables"]["episode"].clone();
+            json!({"data": {"films": [{"title": "A New Hope", "episode": episode}]}})
+        });
+        let db = Connection::open_in_memory()?;
+        graphql::load_module(&db)?;
+
+        let mut s =
+            db.prepare("SELECT path, key, value, type, parent FROM graphql_query(?, ?, ?)")?;
+        let rows: Vec<(String, SqlValue, SqlValue, String, Option<String>)> = s
+            .query((
+                &url,
+                "query Films($episode: Int) { films { title episode } }",
+                r#"{"episode": 4}"#,
+            ))?
+            .map(|row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                ))
+            })
+            .collect()?;
+        let text = |value: &str| SqlValue::Text(value.to_owned());
+        assert_eq!(
+            rows,
+            vec![
+                (
+                    "$".to_owned(),
+                    SqlValue::Null,
+                    text(r#"{"films":[{"episode":4,"title":"A New Hope"}]}"#),
+                    "object".to_owned(),
+                    None
+                ),
+                (
+                    "$.films".to_owned(),
+                    text("films"),
+                    text(r#"[{"episode":4,"title":"A New Hope"}]"#),
+                    "array".to_owned(),
+                    Some("$".to_owned())
+                ),
+                (
+                    "$.films[0]".to_owned(),
+                    SqlValue::Integer(0),
+                    text(r#"{"episode":4,"title":"A New Hope"}"#),
+                    "object".to_owned(),
+                    Some("$.films".to_owned())
+                ),
+                (
+                    "$.films[0].episode".to_owned(),
+                    text("episode"),
+                    SqlValue::Integer(4),
+                    "integer".to_owned(),
+                    Some("$.films[0]".to_owned())
+                ),
+                (
+                    "$.films[0].title".to_owned(),
+                    text("title"),
+                    text("A New Hope"),
+                    "text".to_owned(),
+                    Some("$.films[0]".to_owned())
+                ),
+            ]
+        );
+        let request = requests.lock().unwrap().pop().unwrap();
+        assert_eq!(request["variables"], json!({"episode": 4}));
+
+        // the variables are optional
+        let titles: Vec<String> = db
+            .prepare(
+                "SELECT value FROM graphql_query(?, 'query { films { title } }')
+                 WHERE type = 'text'",
+            )?
+            .query([&url])?
+            .map(|row| row.get(0))
+            .collect()?;
+        assert_eq!(titles, vec!["A New Hope".to_owned()]);
+        assert!(db
+            .prepare("SELECT * FROM graphql_query(?)")?
+            .query([&url])?
+            .next()
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_fetch_function() -> Result<()> {
+        let (url, requests) = mock_server(|request| match request["variables"].get("id") {
+            Some(id) => json!({"data": {"film": {"id": id, "title": "A New Hope"}}}),
+            None => json!({"data": null, "errors": [{"message": "missing id"}]}),
+        });
+        let db = Connection::open_in_memory()?;
+        graphql::load_module(&db)?;
+
+        let title: String = db.query_row(
+            "SELECT json_extract(graphql_fetch(?, ?, ?, ?), '$.film.title')",
+            (
+                &url,
+                "query Film($id: ID!) { film(id: $id) { id title } }",
+                r#"{"id": "1"}"#,
+                r#"{"X-Tenant": "abc"}"#,
+            ),
+            |row| row.get(0),
+        )?;
+        assert_eq!(title, "A New Hope");
+        let request = requests.lock().unwrap().pop().unwrap();
+        assert_eq!(request["variables"], json!({"id": "1"}));
+
+        let err = db
+            .query_row(
+                "SELECT graphql_fetch(?, 'query { film { id } }')",
+                [&url],
+                |row| row.get::<_, String>(0),
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains("missing id"));
+        assert!(db
+            .query_row("SELECT graphql_fetch(?)", [&url], |row| row
+                .get::<_, String>(0))
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_mutations() -> Result<()> {
+        let (url, requests) =
+            mock_server(|request| match request["operationName"].as_str().unwrap() {
+                "Users" => json!({"data": {"users": [
+                    {"id": "1", "name": "Ann"},
+                    {"id": "2", "name": "Bob"},
+                ]}}),
+                "AddUser" => json!({"data": {"addUser": {"id": "3"}}}),
+                "UpdateUser" => json!({"data": {"updateUser": {"id": "1"}}}),
+                _ => json!({"data": {"deleteUser": true}}),
+            });
+        let db = Connection::open_in_memory()?;
+        graphql::load_module(&db)?;
+        db.execute_batch(&format!(
+            "CREATE VIRTUAL TABLE users USING graphql(url='{url}',
+                query='query Users {{ users {{ id name }} }}',
+                unique_key=id,
+                insert_mutation='mutation AddUser($name: String!) {{ addUser(name: $name) {{ id }} }}',
+                update_mutation='mutation UpdateUser($id: ID!, $name: String) {{
+                  updateUser(id: $id, name: $name) {{ id }}
+                }}',
+                delete_mutation='mutation DeleteUser($id: ID!) {{ deleteUser(id: $id) }}')"
+        ))?;
+
+        db.execute("INSERT INTO users (name) VALUES ('Cid')", [])?;
+        let request = requests.lock().unwrap().pop().unwrap();
+        assert_eq!(request["operationName"], "AddUser");
+        assert_eq!(request["variables"], json!({"name": "Cid"}));
+
+        assert_eq!(
+            db.execute("UPDATE users SET name = 'Ada' WHERE id = '1'", [])?,
+            1
+        );
+        let request = requests.lock().unwrap().pop().unwrap();
+        assert_eq!(request["operationName"], "UpdateUser");
+        assert_eq!(request["variables"], json!({"id": "1", "name": "Ada"}));
+
+        assert_eq!(db.execute("DELETE FROM users WHERE name = 'Bob'", [])?, 1);
+        let request = requests.lock().unwrap().pop().unwrap();
+        assert_eq!(request["operationName"], "DeleteUser");
+        assert_eq!(request["variables"], json!({"id": "2"}));
+
+        assert!(db.execute("UPDATE users SET id = '5'", []).is_err());
+
+        // tables without mutations are read-only
+        db.execute_batch(&format!(
+            "CREATE VIRTUAL TABLE films USING graphql(url='{url}',
+                query='query Users {{ users {{ id name }} }}')"
+        ))?;
+        let err = db.execute("DELETE FROM films", []).unwrap_err();
+        assert!(err.to_string().contains("read-only"), "{err}");
+        // update and delete mutations identify rows by the unique key
+        assert!(db
+            .execute_batch(&format!(
+                "CREATE VIRTUAL TABLE people USING graphql(url='{url}',
+                    query='query Users {{ users {{ id name }} }}',
+                    delete_mutation='mutation DeleteUser($id: ID!) {{ deleteUser(id: $id) }}')"
+            ))
+            .is_err());
+        // a variable column can't identify the rows
+        let err = db
+            .execute_batch(&format!(
+                "CREATE VIRTUAL TABLE user USING graphql(url='{url}',
+                    query='query User($id: ID!) {{ user(id: $id) {{ name }} }}',
+                    unique_key=id,
+                    delete_mutation='mutation DeleteUser($id: ID!) {{ deleteUser(id: $id) }}')"
+            ))
+            .unwrap_err();
+        assert!(err.to_string().contains("`id` is a variable"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_mutation_results() -> Result<()> {
+        let (url, requests) = mock_server(|request| {
+            match request["operationName"].as_str().unwrap() {
+                "Users" => json!({"data": {"users": [{"id": "1", "name": "Ann"}]}}),
+                // `message` of a successful payload isn't an error
+                "AddUser" => json!({"data": {"addUser": {
+                    "__typename": "User",
+                    "id": "42",
+                    "message": "welcome",
+                }}}),
+                "AddNewUser" => json!({"data": {"addUser": {"id": "7"}}}),
+                "AddName" => json!({"data": {"addName": true}}),
+                _ => json!({"data": {"deleteUser": {
+                    "__typename": "RecordNotFound",
+                    "message": "no user 1",
+                }}}),
+            }
+        });
+        let db = Connection::open_in_memory()?;
+        graphql::load_module(&db)?;
+        db.execute_batch(&format!(
+            "CREATE VIRTUAL TABLE users USING graphql(url='{url}',
+                query='query Users {{ users {{ id name }} }}',
+                unique_key=id,
+                error_types='RecordNotFound, ValidationError',
+                insert_mutation='mutation AddUser($name: String!) {{
+                  addUser(name: $name) {{
+                    ... on User {{ id message }}
+                    ... on ValidationError {{ message }}
+                  }}
+                }}',
+                delete_mutation='mutation DeleteUser($id: ID!) {{
+                  deleteUser(id: $id) {{
+                    ... on User {{ id }}
+                    ... on RecordNotFound {{ message }}
+                  }}
+                }}');
+             CREATE VIRTUAL TABLE new_users USING graphql(url='{url}',
+                query='query Users {{ users {{ id name }} }}',
+                insert_mutation='mutation AddNewUser($name: String!) {{
+                  addUser(name: $name) {{ id }}
+                }}');
+             CREATE VIRTUAL TABLE names USING graphql(url='{url}',
+                query='query Users {{ users {{ name }} }}',
+                insert_mutation='mutation AddName($name: String!) {{ addName(name: $name) }}')"
+        ))?;
+
+        // the server-assigned ID is the rowid of a table without `update_mutation` and
+        // `delete_mutation`
+        db.execute("INSERT INTO new_users (name) VALUES ('Cid')", [])?;
+        assert_eq!(db.last_insert_rowid(), 7);
+        // inserts without an integer ID and into WITHOUT ROWID tables keep it
+        db.execute("INSERT INTO names (name) VALUES ('Cid')", [])?;
+        assert_eq!(db.last_insert_rowid(), 7);
+        db.execute("INSERT INTO users (name) VALUES ('Cid')", [])?;
+        assert_eq!(db.last_insert_rowid(), 7);
+        let request = requests.lock().unwrap().pop().unwrap();
+        assert!(request["query"].as_str().unwrap().contains("__typename"));
+
+        // the row is only named by its key
+        let err = db.execute("DELETE FROM users", []).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"`delete_mutation` failed for {"id":"1"}: RecordNotFound: no user 1"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_aliases() -> Result<()> {
+        let (url, _) = mock_server(|_| {
+            json!({"data": {"me": {
+                "primaryName": "Ann",
+                "short": {"title": "Dr"},
+                "long": {"title": "Doctor"},
+            }}})
+        });
+        let db = Connection::open_in_memory()?;
+        graphql::load_module(&db)?;
+        db.execute_batch(&format!(
+            "CREATE VIRTUAL TABLE me USING graphql(url='{url}',
+                query='query Me {{
+                  me: user(id: 1) {{
+                    primaryName: name
+                    short: title(format: SHORT) {{ title }}
+                    long: title(format: LONG) {{ title }}
+                  }}
+                }}')"
+        ))?;
+        let row: (String, String, String) = db.query_row(
+            "SELECT primaryName, short_title, long_title FROM me",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )?;
+        assert_eq!(
+            row,
+            ("Ann".to_owned(), "Dr".to_owned(), "Doctor".to_owned())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_union_columns() -> Result<()> {
+        let (url, _) = mock_server(|_| {
+            json!({"data": {"documents": [
+                {"__typename": "Invoice", "id": "1", "total": 9.5},
+                {"__typename": "Requisition", "id": "2", "status": "OPEN"},
+            ]}})
+        });
+        let db = Connection::open_in_memory()?;
+        graphql::load_module(&db)?;
+        db.execute_batch(&format!(
+            "CREATE VIRTUAL TABLE documents USING graphql(url='{url}',
+                query='query Documents {{
+                  documents {{
+                    ... on Invoice {{ id total }}
+                    ... on Requisition {{ id status }}
+                  }}
+                }}')"
+        ))?;
+        let mut s = db.prepare("SELECT * FROM documents")?;
+        assert_eq!(
+            s.column_names(),
+            vec!["__typename", "id", "total", "status"]
+        );
+        let rows: Vec<(String, String, Option<f64>, Option<String>)> = s
+            .query([])?
+            .map(|row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))
+            .collect()?;
+        assert_eq!(
+            rows,
+            vec![
+                ("Invoice".to_owned(), "1".to_owned(), Some(9.5), None),
+                (
+                    "Requisition".to_owned(),
+                    "2".to_owned(),
+                    None,
+                    Some("OPEN".to_owned())
+                ),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_rows_path() -> Result<()> {
+        let (url, _) = mock_server(|_| {
+            json!({"data": {"orders": [
+                {"id": 1, "customer": {"name": "Ann"}, "lines": [
+                    {"item": "a", "shipments": [{"day": "mon"}, {"day": "tue"}]},
+                    {"item": "b", "shipments": [{"day": "wed"}]},
+                ]},
+                {"id": 2, "customer": {"name": "Bob"}, "lines": []},
+                {"id": 3, "customer": {"name": "Cid"}, "lines": [
+                    {"item": "c", "shipments": null},
+                ]},
+            ]}})
+        });
+        let db = Connection::open_in_memory()?;
+        graphql::load_module(&db)?;
+        db.execute_batch(&format!(
+            "CREATE VIRTUAL TABLE shipments USING graphql(url='{url}',
+                query='query Orders {{
+                  orders {{ id customer {{ name }} lines {{ item shipments {{ day }} }} }}
+                }}',
+                rows=lines.shipments)"
+        ))?;
+        let rows: Vec<(i64, String, String, String)> = db
+            .prepare("SELECT * FROM shipments LIMIT 10")?
+            .query([])?
+            .map(|row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))
+            .collect()?;
+        let row = |id, name: &str, item: &str, day: &str| {
+            (id, name.to_owned(), item.to_owned(), day.to_owned())
+        };
+        assert_eq!(
+            rows,
+            vec![
+                row(1, "Ann", "a", "mon"),
+                row(1, "Ann", "a", "tue"),
+                row(1, "Ann", "b", "wed"),
+            ]
+        );
+
+        let err = db
+            .execute_batch(&format!(
+                "CREATE VIRTUAL TABLE invalid USING graphql(url='{url}',
+                    query='query Orders {{ orders {{ id }} }}', rows=lines)"
+            ))
+            .unwrap_err();
+        assert!(err.to_string().contains("`rows` path `lines`"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_columns() -> Result<()> {
+        let (url, requests) = mock_server(|_| {
+            json!({"data": {"orders": [
+                {"id": 1, "customer": {"name": "Ann"}, "tags": ["new", "paid"]},
+                {"id": 2, "customer": null, "tags": []},
+            ]}})
+        });
+        let db = Connection::open_in_memory()?;
+        graphql::load_module(&db)?;
+        db.execute_batch(&format!(
+            "CREATE VIRTUAL TABLE orders USING graphql(url='{url}',
+                query='query Orders {{ orders {{ id customer {{ name }} tags }} }}',
+                json_columns='customer,tags')"
+        ))?;
+        let rows: Vec<(i64, Option<String>, i64, String)> = db
+            .prepare(
+                "SELECT id, customer ->> '$.name',
+                    (SELECT count(*) FROM json_each(tags)), json_object('c', json(customer))
+                FROM orders",
+            )?
+            .query([])?
+            .map(|row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))
+            .collect()?;
+        assert_eq!(
+            rows,
+            vec![
+                (
+                    1,
+                    Some("Ann".to_owned()),
+                    2,
+                    r#"{"c":{"name":"Ann"}}"#.to_owned()
+                ),
+                (2, None, 0, r#"{"c":null}"#.to_owned()),
+            ]
+        );
+        assert!(requests.lock().unwrap()[0]["query"]
+            .as_str()
+            .unwrap()
+            .contains("name"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_rows_path_limit() -> Result<()> {
+        let (url, requests) = mock_server(|request| {
+            let offset = request["variables"]["_apisql_offset"].as_u64().unwrap_or(0) as usize;
+            let first = request["variables"]["first"].as_u64().unwrap_or(10) as usize;
+            let orders = [
+                json!({"lines": [{"item": "a"}, {"item": "b"}]}),
+                json!({"lines": [{"item": "c"}]}),
+            ];
+            let orders: Vec<Value> = orders.into_iter().skip(offset).take(first).collect();
+            json!({"data": {"orders": orders}})
+        });
+        let db = Connection::open_in_memory()?;
+        graphql::load_module(&db)?;
+        db.execute_batch(&format!(
+            "CREATE VIRTUAL TABLE lines USING graphql(url='{url}', limit_arg=first,
+                offset_arg=offset, rows=lines,
+                query='query Orders($first: Int) {{ orders(first: $first) {{ lines {{ item }} }} }}')"
+        ))?;
+
+        // the server would limit the orders, SQLite limits the unnested rows
+        let items = |sql: &str| -> Result<Vec<String>> {
+            db.prepare(sql)?.query([])?.map(|row| row.get(0)).collect()
+        };
+        assert_eq!(items("SELECT lines_item FROM lines LIMIT 1")?, vec!["a"]);
+        assert_eq!(
+            items("SELECT lines_item FROM lines LIMIT 2 OFFSET 1")?,
+            vec!["b", "c"]
+        );
+        for request in requests.lock().unwrap().iter() {
+            assert_eq!(request["variables"], json!({}));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_declared_columns() -> Result<()> {
+        let db = Connection::open_in_memory()?;
+        graphql::load_module(&db)?;
+        db.execute_batch(
+            "CREATE VIRTUAL TABLE orders USING graphql(url='http://localhost:8000/graphql',
+                query='query Orders($first: Int = 10, $after: String) {
+                  orders(first: $first, after: $after) { id total }
+                }')",
+        )?;
+        let mut s = db.prepare("SELECT name, type, hidden FROM pragma_table_xinfo('orders')")?;
+        let columns: Vec<(String, String, bool)> = s
+            .query([])?
+            .map(|row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .collect()?;
+        assert_eq!(
+            columns,
+            vec![
+                ("id".to_owned(), "".to_owned(), false),
+                ("total".to_owned(), "".to_owned(), false),
+                ("first".to_owned(), "INTEGER".to_owned(), true),
+                ("after".to_owned(), "TEXT".to_owned(), true),
+            ]
+        );
+
+        let err = db
+            .execute_batch(
+                "CREATE VIRTUAL TABLE misspelled USING graphql(url='http://localhost:8000/graphql',
+                    query='query Orders { orders { id } }', bearer_tokn=abc)",
+            )
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("unknown option `bearer_tokn`"),
+            "{err}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_to_sql_value() {
+        use rusqlite::types::Value as SqlValue;
+        use serde_json::json;
+
+        assert_eq!(graphql::to_sql_value(&json!(null)), SqlValue::Null);
+        assert_eq!(graphql::to_sql_value(&json!(42)), SqlValue::Integer(42));
+        assert_eq!(graphql::to_sql_value(&json!(1.5)), SqlValue::Real(1.5));
+        assert_eq!(graphql::to_sql_value(&json!(true)), SqlValue::Integer(1));
+        assert_eq!(graphql::to_sql_value(&json!(false)), SqlValue::Integer(0));
+        assert_eq!(
+            graphql::to_sql_value(&json!("text")),
+            SqlValue::Text("text".to_owned())
+        );
+        assert_eq!(
+            graphql::to_sql_value(&json!(["a", "b"])),
+            SqlValue::Text(r#"["a","b"]"#.to_owned())
+        );
+    }
 }
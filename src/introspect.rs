@@ -0,0 +1,328 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+use crate::parse_query::{ColumnType, QueryDetails};
+
+/// Reduced version of the standard introspection query, only the parts needed to resolve the
+/// types of the result paths are requested.
+pub const INTROSPECTION_QUERY: &str = r#"
+query IntrospectionQuery {
+  __schema {
+    queryType { name }
+    types {
+      kind
+      name
+      fields(includeDeprecated: true) {
+        name
+        type { ...TypeRef }
+      }
+      possibleTypes { name }
+    }
+  }
+}
+
+fragment TypeRef on __Type {
+  kind
+  name
+  ofType {
+    kind
+    name
+    ofType {
+      kind
+      name
+      ofType {
+        kind
+        name
+        ofType {
+          kind
+          name
+          ofType {
+            kind
+            name
+          }
+        }
+      }
+    }
+  }
+}
+"#;
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum TypeKind {
+    Scalar,
+    Object,
+    Interface,
+    Union,
+    Enum,
+    InputObject,
+    List,
+    NonNull,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TypeRef {
+    pub kind: TypeKind,
+    pub name: Option<String>,
+    pub of_type: Option<Box<TypeRef>>,
+}
+
+impl TypeRef {
+    /// Strips the non-null wrapper
+    fn nullable(&self) -> &TypeRef {
+        match (&self.kind, &self.of_type) {
+            (TypeKind::NonNull, Some(of_type)) => of_type.nullable(),
+            _ => self,
+        }
+    }
+
+    /// Strips all list and non-null wrappers
+    fn named(&self) -> &TypeRef {
+        match &self.of_type {
+            Some(of_type) => of_type.named(),
+            None => self,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct FieldDetails {
+    name: String,
+    #[serde(rename = "type")]
+    field_type: TypeRef,
+}
+
+#[derive(Deserialize, Debug)]
+struct NamedType {
+    name: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct FullType {
+    name: String,
+    fields: Option<Vec<FieldDetails>>,
+    possible_types: Option<Vec<NamedType>>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct SchemaDetails {
+    query_type: NamedType,
+    types: Vec<FullType>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct IntrospectionData {
+    #[serde(rename = "__schema")]
+    schema: SchemaDetails,
+}
+
+/// Server schema as returned by the introspection query
+pub struct Schema {
+    query_type: String,
+    types: HashMap<String, FullType>,
+}
+
+impl Schema {
+    /// Builds the schema from the `data` object of the introspection response
+    pub fn from_response(data: &serde_json::Value) -> anyhow::Result<Schema> {
+        let data = IntrospectionData::deserialize(data)?;
+        Ok(Schema {
+            query_type: data.schema.query_type.name,
+            types: data
+                .schema
+                .types
+                .into_iter()
+                .map(|it| (it.name.clone(), it))
+                .collect(),
+        })
+    }
+
+    /// Finds a field on a type. For unions and interfaces the field is also looked up on all
+    /// possible types, i.e. fields selected through inline fragments.
+    fn find_field(&self, type_name: &str, field_name: &str) -> Option<&FieldDetails> {
+        let full_type = self.types.get(type_name)?;
+        let field = full_type
+            .fields
+            .iter()
+            .flatten()
+            .find(|it| it.name == field_name);
+        if field.is_some() {
+            return field;
+        }
+        full_type
+            .possible_types
+            .iter()
+            .flatten()
+            .find_map(|it| self.find_field(&it.name, field_name))
+    }
+
+    fn resolve_field(&self, type_name: &str, field_name: &str) -> anyhow::Result<&TypeRef> {
+        let Some(field) = self.find_field(type_name, field_name) else {
+            return Err(anyhow::Error::msg(format!(
+                "Unknown field `{field_name}` on type `{type_name}`"
+            )));
+        };
+        Ok(&field.field_type)
+    }
+
+    fn column_type(&self, field_type: &TypeRef) -> ColumnType {
+        let field_type = field_type.nullable();
+        match field_type.kind {
+            TypeKind::Scalar => {
+                ColumnType::from_graphql_type(field_type.name.as_deref().unwrap_or_default())
+            }
+            TypeKind::Enum => ColumnType::Text,
+            _ => ColumnType::Any,
+        }
+    }
+
+    /// Checks all result paths against the schema and sets their column types
+    pub fn apply(&self, details: &mut QueryDetails) -> anyhow::Result<()> {
+        let endpoint = self.resolve_field(&self.query_type, &details.endpoint_field)?;
+        for result in details.results.iter_mut() {
+            // __typename is available on every composite type
+            if result.fields.last().map(|it| it.as_str()) == Some("__typename") {
+                result.column_type = ColumnType::Text;
+                result.graphql_type = Some("String".to_owned());
+                continue;
+            }
+            let mut current = endpoint;
+            for segment in &result.fields {
+                let Some(type_name) = current.named().name.as_deref() else {
+                    return Err(anyhow::Error::msg(format!(
+                        "Failed to resolve type of `{}`",
+                        result.to_string()
+                    )));
+                };
+                current = self.resolve_field(type_name, segment)?;
+            }
+            let named = current.named();
+            if result.json {
+                // any type can be serialized
+                result.graphql_type = named.name.clone();
+                continue;
+            }
+            if matches!(
+                named.kind,
+                TypeKind::Object | TypeKind::Interface | TypeKind::Union
+            ) {
+                return Err(anyhow::Error::msg(format!(
+                    "Field `{}` of type `{}` must have a selection of subfields",
+                    result.to_string(),
+                    named.name.as_deref().unwrap_or_default()
+                )));
+            }
+            result.column_type = self.column_type(current);
+            result.graphql_type = named.name.clone();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use crate::parse_query::{parse, ColumnType};
+
+    use super::Schema;
+
+    fn schema() -> Schema {
+        let scalar = |name: &str| json!({"kind": "SCALAR", "name": name, "ofType": null});
+        let non_null = |of_type: serde_json::Value| json!({"kind": "NON_NULL", "name": null, "ofType": of_type});
+        let list =
+            |of_type: serde_json::Value| json!({"kind": "LIST", "name": null, "ofType": of_type});
+        let object = |name: &str| json!({"kind": "OBJECT", "name": name, "ofType": null});
+        Schema::from_response(&json!({
+          "__schema": {
+            "queryType": { "name": "Query" },
+            "types": [
+              {
+                "kind": "OBJECT",
+                "name": "Query",
+                "fields": [
+                  { "name": "orders", "type": non_null(list(non_null(object("Order")))) },
+                ],
+                "possibleTypes": null
+              },
+              {
+                "kind": "OBJECT",
+                "name": "Order",
+                "fields": [
+                  { "name": "id", "type": non_null(scalar("ID")) },
+                  { "name": "price", "type": scalar("Float") },
+                  { "name": "count", "type": scalar("Int") },
+                  { "name": "created", "type": scalar("DateTime") },
+                  { "name": "status", "type": {"kind": "ENUM", "name": "Status", "ofType": null} },
+                  { "name": "tags", "type": list(scalar("String")) },
+                  { "name": "customer", "type": object("Customer") },
+                ],
+                "possibleTypes": null
+              },
+              {
+                "kind": "OBJECT",
+                "name": "Customer",
+                "fields": [
+                  { "name": "active", "type": scalar("Boolean") },
+                ],
+                "possibleTypes": null
+              },
+            ]
+          }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_apply_schema() {
+        let mut details = parse(
+            r#"query Orders {
+              orders { id price count created status tags customer { active } __typename }
+            }"#,
+        )
+        .unwrap();
+        schema().apply(&mut details).unwrap();
+        let types: Vec<ColumnType> = details.results.iter().map(|it| it.column_type).collect();
+        assert_eq!(
+            types,
+            vec![
+                ColumnType::Text,
+                ColumnType::Real,
+                ColumnType::Integer,
+                ColumnType::Any,
+                ColumnType::Text,
+                ColumnType::Any,
+                ColumnType::Integer,
+                ColumnType::Text,
+            ]
+        );
+
+        // aliases are resolved by their field names
+        let mut details = parse(
+            r#"query Orders {
+              list: orders { total: price buyer: customer { isActive: active } }
+            }"#,
+        )
+        .unwrap();
+        schema().apply(&mut details).unwrap();
+        assert_eq!(details.results[0].to_string(), "total");
+        assert_eq!(details.results[0].column_type, ColumnType::Real);
+        assert_eq!(details.results[1].to_string(), "buyer_isActive");
+        assert_eq!(details.results[1].column_type, ColumnType::Integer);
+    }
+
+    #[test]
+    fn test_unknown_field() {
+        let mut details = parse("query Orders { orders { id total } }").unwrap();
+        let err = schema().apply(&mut details).unwrap_err();
+        assert_eq!(err.to_string(), "Unknown field `total` on type `Order`");
+
+        let mut details = parse("query Orders { orders { customer } }").unwrap();
+        assert!(schema().apply(&mut details).is_err());
+    }
+}
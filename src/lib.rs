@@ -2,18 +2,27 @@
 use std::os::raw::{c_char, c_int};
 
 use rusqlite::ffi;
-use rusqlite::{to_sqlite_error, Connection, Result};
+use rusqlite::{Connection, Result};
 
+mod estimate;
+mod fetch_function;
+mod filter;
 mod graphql;
+mod introspect;
+mod mutation;
 mod optimize_query;
+mod order_by;
+mod pagination;
 mod parse_query;
+mod query_function;
+mod stream;
+mod transport;
 
 #[cfg(feature = "loadable_extension")]
-fn extension_init(db: *mut ffi::sqlite3, p_api: *mut ffi::sqlite3_api_routines) -> Result<()> {
-    let db = unsafe { Connection::extension_init2(db, p_api)? };
+fn extension_init(db: Connection) -> Result<bool> {
     graphql::load_module(&db)?;
 
-    Ok(())
+    Ok(false)
 }
 
 /// # test
@@ -32,10 +41,5 @@ pub extern "C" fn sqlite3_extension_init(
     pz_err_msg: *mut *mut c_char,
     p_api: *mut ffi::sqlite3_api_routines,
 ) -> c_int {
-    if p_api.is_null() {
-        return ffi::SQLITE_ERROR;
-    } else if let Err(err) = extension_init(db, p_api) {
-        return unsafe { to_sqlite_error(&err, pz_err_msg) };
-    }
-    ffi::SQLITE_OK
+    unsafe { Connection::extension_init2(db, pz_err_msg, p_api, extension_init) }
 }
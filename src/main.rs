@@ -1,5 +1,15 @@
+mod estimate;
+mod fetch_function;
+mod filter;
 mod graphql;
+mod introspect;
+mod mutation;
 mod optimize_query;
+mod order_by;
+mod pagination;
 mod parse_query;
+mod query_function;
+mod stream;
+mod transport;
 
 fn main() {}
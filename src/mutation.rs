@@ -0,0 +1,155 @@
+use rusqlite::{Error, Result};
+use serde_json::{json, Map, Value};
+
+use crate::{
+    parse_query::{parse_document, parse_mutation, QueryDetails},
+    transport::Transport,
+};
+
+/// A mutation document that is sent for an `INSERT`, `UPDATE` or `DELETE`, e.g.
+/// `insert_mutation='mutation AddUser($name: String!) { addUser(name: $name) { id } }'`.
+/// Its variables are bound to the column values of the same name.
+#[derive(Default, Clone)]
+pub struct Mutation {
+    /// Module option that specified the mutation
+    pub option: String,
+    /// The document that is sent, `__typename` is selected next to inline fragments
+    pub query: String,
+    pub details: QueryDetails,
+    /// Result types that are errors, e.g. `RecordNotFound`, set by `resolve`
+    pub error_types: Vec<String>,
+}
+
+impl Mutation {
+    pub fn from_option(option: &str, value: &str) -> Result<Mutation> {
+        let invalid = |err: anyhow::Error| Error::ModuleError(format!("invalid `{option}`: {err}"));
+        let details = parse_mutation(value).map_err(invalid)?;
+        let query = parse_document(value).map_err(invalid)?.to_string();
+        Ok(Mutation {
+            option: option.to_owned(),
+            query,
+            details,
+            error_types: vec![],
+        })
+    }
+
+    /// Checks that the required variables can be bound to the `columns`
+    pub fn resolve(&mut self, columns: &[String], error_types: &[String]) -> Result<()> {
+        self.error_types = error_types.to_vec();
+        for variable in &self.details.variables {
+            if variable.is_required() && !columns.contains(&variable.name) {
+                return Err(Error::ModuleError(format!(
+                    "variable `${}` of `{}` doesn't match a column",
+                    variable.name, self.option
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Binds the variables to the `(column, value)` pairs, variables without a value keep their
+    /// default
+    pub fn variables(&self, values: &[(&str, Value)]) -> Result<Map<String, Value>> {
+        let mut variables = Map::new();
+        for variable in &self.details.variables {
+            let Some((_, value)) = values.iter().find(|(name, _)| *name == variable.name) else {
+                continue;
+            };
+            if value.is_null() && !variable.is_required() {
+                continue;
+            }
+            let value = variable.var_type.coerce(value.clone()).map_err(|err| {
+                Error::ModuleError(format!(
+                    "`{}` variable `${}`: {err}",
+                    self.option, variable.name
+                ))
+            })?;
+            variables.insert(variable.name.clone(), value);
+        }
+        Ok(variables)
+    }
+
+    /// Sends the mutation and returns the result of its endpoint.
+    /// Errors, including error types of a union result, name the `row` of the failed mutation.
+    pub fn execute(
+        &self,
+        transport: &Transport,
+        variables: Map<String, Value>,
+        row: &str,
+    ) -> Result<Value> {
+        let failed =
+            |err: String| Error::ModuleError(format!("`{}` failed for {row}: {err}", self.option));
+        let mut response = transport
+            .post(&json!({
+              "operationName": self.details.operation_name,
+              "query": self.query,
+              "variables": variables
+            }))
+            .map_err(|err| failed(err.to_string()))?;
+        let result = response
+            .get_mut("data")
+            .and_then(|it| it.get_mut(&self.details.endpoint_name))
+            .map(Value::take)
+            .unwrap_or_default();
+        if let Some(err) = self.error_message(&result) {
+            return Err(failed(err));
+        }
+        Ok(result)
+    }
+
+    /// Value of a result column of the mutation, e.g. the id of an inserted row
+    pub fn result_value<'a>(&self, result: &'a Value, column: &str) -> Option<&'a Value> {
+        let path = self
+            .details
+            .results
+            .iter()
+            .find(|it| it.to_string() == column)?;
+        value_at(result, &path.path).filter(|it| !it.is_null())
+    }
+
+    /// Returns the error if the result is one of the `error_types`, e.g.
+    /// `... on RecordNotFound { message }`. The returned `__typename` tells the type, the
+    /// `message` of the fragment describes the error.
+    fn error_message(&self, result: &Value) -> Option<String> {
+        let returned: Vec<_> = self
+            .details
+            .results
+            .iter()
+            .filter(|it| {
+                let Some(type_condition) = &it.type_condition else {
+                    return false;
+                };
+                let Some((_, parent)) = it.path.split_last() else {
+                    return false;
+                };
+                let type_name = value_at(result, parent)
+                    .and_then(|it| it.get("__typename"))
+                    .and_then(|it| it.as_str());
+                self.error_types.contains(type_condition)
+                    && type_name == Some(type_condition.as_str())
+            })
+            .collect();
+        let type_name = returned.first()?.type_condition.clone()?;
+        let message = returned
+            .iter()
+            .filter(|it| it.fields.last().map(|it| it.as_str()) == Some("message"))
+            .find_map(|it| value_at(result, &it.path).filter(|it| !it.is_null()));
+        Some(match message {
+            Some(Value::String(message)) => format!("{type_name}: {message}"),
+            Some(message) => format!("{type_name}: {message}"),
+            None => type_name,
+        })
+    }
+}
+
+/// Follows the keys of `path`, lists are followed into their first item
+fn value_at<'a>(value: &'a Value, path: &[String]) -> Option<&'a Value> {
+    let mut value = value;
+    for key in path {
+        while let Value::Array(items) = value {
+            value = items.first()?;
+        }
+        value = value.get(key)?;
+    }
+    Some(value)
+}
@@ -1,27 +1,264 @@
 use graphql_parser::{
     parse_query,
-    query::{Definition, Document, OperationDefinition, Selection, SelectionSet},
+    query::{
+        Definition, Document, Field, OperationDefinition, Selection, SelectionSet, Type, Value,
+        VariableDefinition,
+    },
+    Pos,
 };
 
+use crate::parse_query::parse_document;
+
+/// Argument that is added to a field of the query and bound to a (new) query variable
+#[derive(Clone, Debug, PartialEq)]
+pub struct InjectedArgument {
+    /// Path of the field relative to the endpoint, empty for the endpoint itself
+    pub field_path: Vec<String>,
+    /// Argument name followed by the keys of nested input objects,
+    /// e.g. `["filter", "createdAt", "afterOrEqualTo"]`
+    pub argument_path: Vec<String>,
+    pub variable: String,
+    /// GraphQL type of the variable, e.g. `Int!`
+    pub var_type: String,
+    /// Keep an argument that is already present in the query
+    pub keep_existing: bool,
+    /// GraphQL value that is injected instead of the variable, e.g. `{key: createdAt, desc: true}`
+    pub literal: Option<String>,
+}
+
+/// Parses a variable type like `[Int!]!`
+pub fn parse_type(var_type: &str) -> Type<'_, &str> {
+    if let Some(inner) = var_type.strip_suffix('!') {
+        return Type::NonNullType(Box::new(parse_type(inner)));
+    }
+    if let Some(inner) = var_type
+        .strip_prefix('[')
+        .and_then(|it| it.strip_suffix(']'))
+    {
+        return Type::ListType(Box::new(parse_type(inner)));
+    }
+    Type::NamedType(var_type)
+}
+
+fn find_field<'a, 'b>(
+    selection_set: &'b mut SelectionSet<'a, &'a str>,
+    response_key: &str,
+) -> Option<&'b mut Field<'a, &'a str>> {
+    for item in selection_set.items.iter_mut() {
+        match item {
+            Selection::Field(field) => {
+                if field.alias.unwrap_or(field.name) == response_key {
+                    return Some(field);
+                }
+            }
+            Selection::InlineFragment(inline_fragment) => {
+                if let Some(field) = find_field(&mut inline_fragment.selection_set, response_key) {
+                    return Some(field);
+                }
+            }
+            Selection::FragmentSpread(_) => {}
+        }
+    }
+    None
+}
+
+fn find_field_by_path<'a, 'b>(
+    endpoint: &'b mut Field<'a, &'a str>,
+    field_path: &[String],
+) -> anyhow::Result<&'b mut Field<'a, &'a str>> {
+    let mut field = endpoint;
+    for segment in field_path {
+        let Some(child) = find_field(&mut field.selection_set, segment) else {
+            return Err(anyhow::Error::msg(format!(
+                "Field `{}` not found in query",
+                field_path.join(".")
+            )));
+        };
+        field = child;
+    }
+    Ok(field)
+}
+
+/// Sets a (nested) argument value.
+/// Returns false if an existing value has been kept.
+fn set_argument<'a>(
+    arguments: &mut Vec<(&'a str, Value<'a, &'a str>)>,
+    argument_path: &'a [String],
+    value: Value<'a, &'a str>,
+    keep_existing: bool,
+) -> bool {
+    let Some((name, rest)) = argument_path.split_first() else {
+        return false;
+    };
+    let existing = arguments.iter_mut().find(|(key, _)| *key == name.as_str());
+    if rest.is_empty() {
+        match existing {
+            Some(_) if keep_existing => return false,
+            Some((_, existing)) => *existing = value,
+            None => arguments.push((name.as_str(), value)),
+        }
+        return true;
+    }
+    let object = match existing {
+        Some((_, existing)) => existing,
+        None => {
+            arguments.push((name.as_str(), Value::Object(Default::default())));
+            &mut arguments.last_mut().unwrap().1
+        }
+    };
+    if !matches!(object, Value::Object(_)) {
+        if keep_existing {
+            return false;
+        }
+        *object = Value::Object(Default::default());
+    }
+    let Value::Object(fields) = object else {
+        return false;
+    };
+    let mut nested: Vec<(&'a str, Value<'a, &'a str>)> =
+        std::mem::take(fields).into_iter().collect();
+    let updated = set_argument(&mut nested, rest, value, keep_existing);
+    *fields = nested.into_iter().collect();
+    updated
+}
+
+/// Parses a GraphQL value like `{key: createdAt, desc: true}`.
+/// `wrapper` must be the value wrapped with `wrap_value`.
+fn parse_value(wrapper: &str) -> anyhow::Result<Value<'_, &str>> {
+    let parse_result: Document<&str> = parse_query(wrapper)?;
+    let Some(Definition::Operation(OperationDefinition::SelectionSet(selection_set))) =
+        parse_result.definitions.into_iter().next()
+    else {
+        return Err(anyhow::Error::msg("Invalid value"));
+    };
+    let Some(Selection::Field(mut field)) = selection_set.items.into_iter().next() else {
+        return Err(anyhow::Error::msg("Invalid value"));
+    };
+    let Some((_, value)) = field.arguments.pop() else {
+        return Err(anyhow::Error::msg("Invalid value"));
+    };
+    Ok(value)
+}
+
+/// Wraps a value in a query, the parser has no entry point for values
+fn wrap_value(value: &str) -> String {
+    format!("{{ value(value: {value}) }}")
+}
+
+/// Checks that `value` is a valid GraphQL value
+pub fn validate_value(value: &str) -> anyhow::Result<()> {
+    parse_value(&wrap_value(value))
+        .map(|_| ())
+        .map_err(|err| anyhow::Error::msg(format!("Invalid value `{value}`: {err}")))
+}
+
+fn inject_argument<'a>(
+    variable_definitions: &mut Vec<VariableDefinition<'a, &'a str>>,
+    endpoint: &mut Field<'a, &'a str>,
+    injected: &'a InjectedArgument,
+    literal: Option<Value<'a, &'a str>>,
+) -> anyhow::Result<()> {
+    let field = find_field_by_path(endpoint, &injected.field_path)?;
+    let is_literal = literal.is_some();
+    let updated = set_argument(
+        &mut field.arguments,
+        &injected.argument_path,
+        literal.unwrap_or(Value::Variable(injected.variable.as_str())),
+        injected.keep_existing,
+    );
+    // Only declare the variable if it is used, unused variables are rejected by the server
+    if updated
+        && !is_literal
+        && !variable_definitions
+            .iter()
+            .any(|it| it.name == injected.variable)
+    {
+        variable_definitions.push(VariableDefinition {
+            position: Pos::default(),
+            name: injected.variable.as_str(),
+            var_type: parse_type(&injected.var_type),
+            default_value: None,
+        });
+    }
+    Ok(())
+}
+
+/// Returns the value of a (nested) field argument as written in the query, e.g. `$after` or `20`
+pub fn find_argument(
+    query: &str,
+    field_path: &[String],
+    argument_path: &[String],
+) -> anyhow::Result<Option<String>> {
+    let mut parse_result: Document<&str> = parse_document(query)?;
+    let Some(Definition::Operation(OperationDefinition::Query(query))) = parse_result
+        .definitions
+        .iter_mut()
+        .find(|it| matches!(it, Definition::Operation(OperationDefinition::Query(_))))
+    else {
+        return Err(anyhow::Error::msg("No query operation found"));
+    };
+    let Some(Selection::Field(endpoint)) = query.selection_set.items.first_mut() else {
+        return Err(anyhow::Error::msg("Field expected"));
+    };
+    let field = find_field_by_path(endpoint, field_path)?;
+    let Some((name, rest)) = argument_path.split_first() else {
+        return Ok(None);
+    };
+    let Some(mut value) = field
+        .arguments
+        .iter()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value)
+    else {
+        return Ok(None);
+    };
+    for key in rest {
+        let Value::Object(fields) = value else {
+            return Ok(None);
+        };
+        let Some(nested) = fields.get(key.as_str()) else {
+            return Ok(None);
+        };
+        value = nested;
+    }
+    Ok(Some(value.to_string()))
+}
+
+/// `columns` collects the paths of the result columns, a path that is selected by several inline
+/// fragments is a single column. The selections of `json_columns` are kept as a whole.
 fn optimize_fields<'a>(
     selection_set: &mut SelectionSet<'a, &'a str>,
     base: &Vec<String>,
     used_col: u64,
-    result_vars_idx: &mut i32,
+    json_columns: &[Vec<String>],
+    columns: &mut Vec<Vec<String>>,
 ) -> anyhow::Result<()> {
     let mut items_to_remove = Vec::new();
     for (i, item) in selection_set.items.iter_mut().enumerate() {
         match item {
             Selection::Field(field) => {
                 let mut path = base.clone();
-                path.push(field.name.to_string());
-                if field.selection_set.items.is_empty() {
-                    if (used_col >> *result_vars_idx) & 1 == 0 {
+                path.push(field.alias.unwrap_or(field.name).to_string());
+                if field.selection_set.items.is_empty() || json_columns.contains(&path) {
+                    let column = match columns.iter().position(|it| *it == path) {
+                        Some(column) => column,
+                        None => {
+                            columns.push(path);
+                            columns.len() - 1
+                        }
+                    };
+                    // the last bit stands for all columns from 63 on
+                    if (used_col >> column.min(63)) & 1 == 0 {
                         items_to_remove.push(i)
                     }
-                    *result_vars_idx = *result_vars_idx + 1;
                 } else {
-                    optimize_fields(&mut field.selection_set, &path, used_col, result_vars_idx)?;
+                    optimize_fields(
+                        &mut field.selection_set,
+                        &path,
+                        used_col,
+                        json_columns,
+                        columns,
+                    )?;
                 }
             }
             Selection::InlineFragment(inline_fragment) => {
@@ -30,11 +267,15 @@ fn optimize_fields<'a>(
                     &mut inline_fragment.selection_set,
                     base,
                     used_col,
-                    result_vars_idx,
+                    json_columns,
+                    columns,
                 )?;
             }
-            Selection::FragmentSpread(_) => {
-                return Err(anyhow::Error::msg(format!("FragmentSpread not supported")));
+            Selection::FragmentSpread(spread) => {
+                return Err(anyhow::Error::msg(format!(
+                    "Fragment `{}` not inlined",
+                    spread.fragment_name
+                )));
             }
         };
     }
@@ -51,38 +292,173 @@ fn optimize_fields<'a>(
     Ok(())
 }
 
-pub fn optimize_query<'a>(query: &'a str, used_col: u64) -> anyhow::Result<String> {
-    let mut parse_result: Document<'a, &str> = parse_query(query)?;
-    let Some(Definition::Operation(OperationDefinition::Query(query))) =
-        parse_result.definitions.iter_mut().find(|it| match it {
-            Definition::Operation(op) => match op {
-                OperationDefinition::Query(_) => true,
-                _ => false,
-            },
-            Definition::Fragment(_) => false,
-        })
+/// Nesting depth and number of leaf fields of a selection
+fn selection_size<'a>(selection: &Selection<'a, &'a str>) -> (usize, usize) {
+    let (selection_set, nested) = match selection {
+        Selection::Field(field) if field.selection_set.items.is_empty() => return (0, 1),
+        Selection::Field(field) => (&field.selection_set, 1),
+        // fields of a fragment are on the same level as the fragment
+        Selection::InlineFragment(inline_fragment) => (&inline_fragment.selection_set, 0),
+        Selection::FragmentSpread(_) => return (0, 0),
+    };
+    selection_set.items.iter().map(selection_size).fold(
+        (0, 0),
+        |(depth, count), (item_depth, item_count)| {
+            (depth.max(item_depth + nested), count + item_count)
+        },
+    )
+}
+
+/// Reorders the fields so that the server returns scalar fields before nested objects and lists.
+///
+/// Rows are streamed while the response is received, values outside the exploded list must
+/// therefore arrive before the list items. The list is usually the most nested selection.
+fn order_fields<'a>(selection_set: &mut SelectionSet<'a, &'a str>) {
+    for item in selection_set.items.iter_mut() {
+        match item {
+            Selection::Field(field) => order_fields(&mut field.selection_set),
+            Selection::InlineFragment(inline_fragment) => {
+                order_fields(&mut inline_fragment.selection_set)
+            }
+            Selection::FragmentSpread(_) => {}
+        }
+    }
+    // stable sort, fields of the same size keep their order
+    selection_set.items.sort_by_key(selection_size);
+}
+
+fn rename_variable_in_value<'a>(value: &mut Value<'a, &'a str>, from: &str, to: &'a str) {
+    match value {
+        Value::Variable(name) if *name == from => *name = to,
+        Value::List(items) => items
+            .iter_mut()
+            .for_each(|it| rename_variable_in_value(it, from, to)),
+        Value::Object(fields) => fields
+            .values_mut()
+            .for_each(|it| rename_variable_in_value(it, from, to)),
+        _ => {}
+    }
+}
+
+fn rename_variable<'a>(selection_set: &mut SelectionSet<'a, &'a str>, from: &str, to: &'a str) {
+    for item in selection_set.items.iter_mut() {
+        match item {
+            Selection::Field(field) => {
+                for (_, value) in field.arguments.iter_mut() {
+                    rename_variable_in_value(value, from, to);
+                }
+                rename_variable(&mut field.selection_set, from, to);
+            }
+            Selection::InlineFragment(inline_fragment) => {
+                rename_variable(&mut inline_fragment.selection_set, from, to)
+            }
+            Selection::FragmentSpread(_) => {}
+        }
+    }
+}
+
+/// Response key of the `index`th copy of the endpoint in a batched query
+pub fn batch_alias(index: usize) -> String {
+    format!("_apisql_{index}")
+}
+
+/// Variable that replaces `variable` in the `index`th copy of the endpoint in a batched query
+pub fn batch_variable(index: usize) -> String {
+    format!("_apisql_in_{index}")
+}
+
+/// Repeats the endpoint once per value of `variable`, so that all values are fetched with a
+/// single request. The copies are aliased with `batch_alias` and use `batch_variable` instead
+/// of `variable`.
+pub fn batch_query(query: &str, variable: &str, count: usize) -> anyhow::Result<String> {
+    let aliases: Vec<String> = (0..count).map(batch_alias).collect();
+    let variables: Vec<String> = (0..count).map(batch_variable).collect();
+    let mut parse_result: Document<&str> = parse_document(query)?;
+    let Some(Definition::Operation(OperationDefinition::Query(query))) = parse_result
+        .definitions
+        .iter_mut()
+        .find(|it| matches!(it, Definition::Operation(OperationDefinition::Query(_))))
+    else {
+        return Err(anyhow::Error::msg("No query operation found"));
+    };
+    let Some(Selection::Field(endpoint)) = query.selection_set.items.first() else {
+        return Err(anyhow::Error::msg("Field expected"));
+    };
+    let Some(position) = query
+        .variable_definitions
+        .iter()
+        .position(|it| it.name == variable)
+    else {
+        return Err(anyhow::Error::msg(format!(
+            "Variable `{variable}` not found in query"
+        )));
+    };
+    let definition = query.variable_definitions.remove(position);
+
+    let mut items = vec![];
+    for (alias, renamed) in aliases.iter().zip(&variables) {
+        let mut field = endpoint.clone();
+        field.alias = Some(alias.as_str());
+        for (_, value) in field.arguments.iter_mut() {
+            rename_variable_in_value(value, variable, renamed);
+        }
+        rename_variable(&mut field.selection_set, variable, renamed);
+        items.push(Selection::Field(field));
+        query.variable_definitions.push(VariableDefinition {
+            name: renamed.as_str(),
+            default_value: None,
+            ..definition.clone()
+        });
+    }
+    query.selection_set.items = items;
+
+    Ok(format!("{parse_result}"))
+}
+
+pub fn optimize_query(
+    query: &str,
+    used_col: u64,
+    json_columns: &[Vec<String>],
+    injected: &[InjectedArgument],
+) -> anyhow::Result<String> {
+    let literals: Vec<Option<String>> = injected
+        .iter()
+        .map(|it| it.literal.as_deref().map(wrap_value))
+        .collect();
+    let mut parse_result: Document<&str> = parse_document(query)?;
+    let Some(Definition::Operation(OperationDefinition::Query(query))) = parse_result
+        .definitions
+        .iter_mut()
+        .find(|it| matches!(it, Definition::Operation(OperationDefinition::Query(_))))
     else {
         return Err(anyhow::Error::msg("No query operation found"));
     };
 
     // get operation name
     let Some(item) = query.selection_set.items.first_mut() else {
-        return Err(anyhow::Error::msg(format!(
-            "At least on item in query expected",
-        )));
+        return Err(anyhow::Error::msg(
+            "At least one item in query expected".to_string(),
+        ));
     };
     let Selection::Field(field) = item else {
         return Err(anyhow::Error::msg(format!("Field expected ({:?})", item)));
     };
 
-    let mut result_vars_idx = 0;
     optimize_fields(
         &mut field.selection_set,
         &vec![],
         used_col,
-        &mut result_vars_idx,
+        json_columns,
+        &mut vec![],
     )?;
 
+    order_fields(&mut field.selection_set);
+
+    for (injected, literal) in injected.iter().zip(&literals) {
+        let literal = literal.as_deref().map(parse_value).transpose()?;
+        inject_argument(&mut query.variable_definitions, field, injected, literal)?;
+    }
+
     let output = format!("{parse_result}");
     Ok(output)
 }
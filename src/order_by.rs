@@ -0,0 +1,173 @@
+use rusqlite::{Error, Result};
+
+use crate::{
+    filter::parse_argument_target,
+    optimize_query::{find_argument, validate_value, InjectedArgument},
+    parse_query::QueryDetails,
+};
+
+/// Maps SQL `ORDER BY` terms on result columns to the sort argument of the server, e.g.
+/// `order_by_arg=sort` with `order_by='nodes_createdAt={key: createdAt, desc: $desc}'`.
+///
+/// A sort value may contain the placeholders `$desc` (`true` or `false`) and `$direction`
+/// (`ASC` or `DESC`). Several terms are passed on as a list.
+#[derive(Default, Clone, Debug, PartialEq)]
+pub struct OrderBy {
+    /// Path of the field with the sort argument relative to the endpoint
+    pub field_path: Vec<String>,
+    /// Argument name followed by the keys of nested input objects
+    pub argument_path: Vec<String>,
+    /// Result columns and their sort values
+    pub columns: Vec<(String, String)>,
+    /// Indices of the result columns, set by `resolve`
+    pub column_indices: Vec<usize>,
+}
+
+fn sort_value(template: &str, desc: bool) -> String {
+    template
+        .replace("$desc", if desc { "true" } else { "false" })
+        .replace("$direction", if desc { "DESC" } else { "ASC" })
+}
+
+impl OrderBy {
+    /// Parses the `order_by_arg` module option, e.g. `sort` or `films(orderBy)`
+    pub fn set_argument(&mut self, value: &str) -> Result<()> {
+        let Some((field_path, argument_path)) = parse_argument_target(value) else {
+            return Err(Error::ModuleError(format!(
+                "invalid `order_by_arg` `{value}`, expected `[field(]argument[)]`"
+            )));
+        };
+        self.field_path = field_path;
+        self.argument_path = argument_path;
+        Ok(())
+    }
+
+    /// Parses the `order_by` module option, `;` separated `column=value` pairs
+    pub fn set_columns(&mut self, value: &str) -> Result<()> {
+        for mapping in value.split(';').filter(|it| !it.trim().is_empty()) {
+            let Some((column, template)) = mapping.split_once('=') else {
+                return Err(Error::ModuleError(format!(
+                    "invalid `order_by` `{mapping}`, expected `column=value`"
+                )));
+            };
+            let template = template.trim();
+            for desc in [false, true] {
+                validate_value(&sort_value(template, desc))
+                    .map_err(|err| Error::ModuleError(format!("`order_by`: {err}")))?;
+            }
+            self.columns
+                .push((column.trim().to_owned(), template.to_owned()));
+        }
+        Ok(())
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.columns.is_empty()
+    }
+
+    /// Resolves the result columns
+    pub fn resolve(&mut self, query: &str, details: &QueryDetails) -> Result<()> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        if self.argument_path.is_empty() {
+            return Err(Error::ModuleError(
+                "`order_by` requires the sort argument `order_by_arg`".to_owned(),
+            ));
+        }
+        self.column_indices = self
+            .columns
+            .iter()
+            .map(|(column, _)| {
+                details
+                    .results
+                    .iter()
+                    .position(|it| it.to_string() == *column)
+                    .ok_or_else(|| {
+                        Error::ModuleError(format!("unknown `order_by` column `{column}`"))
+                    })
+            })
+            .collect::<Result<_>>()?;
+        let existing = find_argument(query, &self.field_path, &self.argument_path)
+            .map_err(|err| Error::ModuleError(err.to_string()))?;
+        // the variable would be unused once the argument is replaced
+        if let Some(variable) = existing.filter(|it| it.starts_with('$')) {
+            return Err(Error::ModuleError(format!(
+                "the `order_by_arg` argument is bound to `{variable}`, remove it from the query"
+            )));
+        }
+        Ok(())
+    }
+
+    /// Sort value of a result column, None if the column can't be sorted by the server
+    fn template(&self, col: usize) -> Option<&str> {
+        let i = self.column_indices.iter().position(|it| *it == col)?;
+        Some(&self.columns[i].1)
+    }
+
+    /// True if the server can sort by all `(column, desc)` terms
+    pub fn supports(&self, terms: &[(usize, bool)]) -> bool {
+        !terms.is_empty() && terms.iter().all(|(col, _)| self.template(*col).is_some())
+    }
+
+    /// Returns the sort argument for the `(column, desc)` terms
+    pub fn injected_argument(&self, terms: &[(usize, bool)]) -> Option<InjectedArgument> {
+        let values = terms
+            .iter()
+            .map(|(col, desc)| Some(sort_value(self.template(*col)?, *desc)))
+            .collect::<Option<Vec<_>>>()?;
+        let literal = match values.as_slice() {
+            [] => return None,
+            [value] => value.clone(),
+            values => format!("[{}]", values.join(", ")),
+        };
+        Some(InjectedArgument {
+            field_path: self.field_path.clone(),
+            argument_path: self.argument_path.clone(),
+            variable: String::new(),
+            var_type: String::new(),
+            keep_existing: false,
+            literal: Some(literal),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{optimize_query::optimize_query, parse_query::parse};
+
+    use super::OrderBy;
+
+    #[test]
+    fn test_order_by() {
+        let query = r#"query Orders {
+          orders(sort: {key: id}) { nodes { id createdAt } }
+        }"#;
+        let details = parse(query).unwrap();
+        let mut order_by = OrderBy::default();
+        order_by.set_argument("sort").unwrap();
+        order_by
+            .set_columns("nodes_createdAt={key: createdAt, desc: $desc}; nodes_id={key: id}")
+            .unwrap();
+        order_by.resolve(query, &details).unwrap();
+        assert_eq!(order_by.column_indices, vec![1, 0]);
+
+        assert!(order_by.supports(&[(1, true)]));
+        assert!(!order_by.supports(&[(1, true), (2, false)]));
+        let injected = [order_by.injected_argument(&[(1, true)]).unwrap()];
+        let request = optimize_query(query, u64::MAX, &[], &injected).unwrap();
+        assert!(request.contains("query Orders {"));
+        assert!(request.contains("orders(sort: {desc: true, key: createdAt})"));
+
+        let injected = [order_by
+            .injected_argument(&[(0, false), (1, false)])
+            .unwrap()];
+        let request = optimize_query(query, u64::MAX, &[], &injected).unwrap();
+        assert!(request.contains("orders(sort: [{key: id}, {desc: false, key: createdAt}])"));
+
+        assert!(OrderBy::default().set_columns("nodes_id={key: ").is_err());
+        let mut order_by = OrderBy::default();
+        order_by.set_columns("nodes_id={key: id}").unwrap();
+        assert!(order_by.resolve(query, &details).is_err());
+    }
+}
@@ -0,0 +1,563 @@
+use rusqlite::{Error, Result};
+use serde_json::{Map, Value};
+
+use crate::{
+    optimize_query::{find_argument, InjectedArgument},
+    parse_query::QueryDetails,
+};
+
+const DEFAULT_PAGE_SIZE: i64 = 100;
+
+/// Splits a mode option like `relay(page_size=50)` into the mode name and its options
+fn parse_mode(value: &str) -> Result<(&str, Vec<(&str, &str)>)> {
+    let value = value.trim();
+    let Some((mode, options)) = value.split_once('(') else {
+        return Ok((value, vec![]));
+    };
+    let Some(options) = options.trim_end().strip_suffix(')') else {
+        return Err(Error::ModuleError(format!("missing `)` in `{value}`")));
+    };
+    let options = options
+        .split(',')
+        .filter(|it| !it.trim().is_empty())
+        .map(|option| {
+            option
+                .split_once('=')
+                .map(|(key, value)| (key.trim(), value.trim()))
+                .ok_or_else(|| Error::ModuleError(format!("invalid option `{option}`")))
+        })
+        .collect::<Result<_>>()?;
+    Ok((mode.trim(), options))
+}
+
+fn parse_page_size(value: &str) -> Result<i64> {
+    match value.parse::<i64>() {
+        Ok(page_size) if page_size > 0 => Ok(page_size),
+        _ => Err(Error::ModuleError(format!("invalid `page_size`: {value}"))),
+    }
+}
+
+/// Returns the variable bound to a field argument.
+/// If the argument is not in the query it is injected and bound to `variable`.
+fn bind_argument(
+    query: &str,
+    field_path: &[String],
+    argument_path: Vec<String>,
+    variable: &str,
+    var_type: &str,
+    injected: &mut Vec<InjectedArgument>,
+) -> Result<Option<String>> {
+    let existing = find_argument(query, field_path, &argument_path)
+        .map_err(|err| Error::ModuleError(err.to_string()))?;
+    match existing {
+        // A literal value
+        Some(value) => Ok(value.strip_prefix('$').map(|it| it.to_owned())),
+        None => {
+            injected.push(InjectedArgument {
+                field_path: field_path.to_vec(),
+                argument_path,
+                variable: variable.to_owned(),
+                var_type: var_type.to_owned(),
+                keep_existing: true,
+                literal: None,
+            });
+            Ok(Some(variable.to_owned()))
+        }
+    }
+}
+
+/// Binds the query variable `name` or the (nested) endpoint argument `name`, e.g. `page.offset`.
+/// `option` names the module option in errors.
+fn bind_variable(
+    query: &str,
+    details: &QueryDetails,
+    name: &str,
+    variable: &str,
+    option: &str,
+    injected: &mut Vec<InjectedArgument>,
+) -> Result<String> {
+    if details.variables.iter().any(|it| it.name == name) {
+        return Ok(name.to_owned());
+    }
+    let argument_path = name.split('.').map(|it| it.to_owned()).collect();
+    bind_argument(query, &[], argument_path, variable, "Int", injected)?.ok_or_else(|| {
+        Error::ModuleError(format!(
+            "`{option}` requires the `{name}` argument to be a variable"
+        ))
+    })
+}
+
+/// Relay cursor connection, see https://relay.dev/graphql/connections.htm
+#[derive(Clone, Debug, PartialEq)]
+pub struct RelayPagination {
+    pub page_size: i64,
+    /// Path of the connection field relative to the endpoint
+    pub connection_path: Vec<String>,
+    /// Variable bound to the `first` argument, None if `first` is a literal in the query
+    pub first_var: Option<String>,
+    /// Variable bound to the `after` argument
+    pub after_var: String,
+    /// Arguments that need to be added to the query
+    pub injected: Vec<InjectedArgument>,
+    /// Indices of the `pageInfo` result columns
+    pub page_info_results: Vec<usize>,
+}
+
+/// Offset based pagination, pages are fetched until a page has less than `page_size` items
+#[derive(Clone, Debug, PartialEq)]
+pub struct OffsetPagination {
+    pub page_size: i64,
+    /// Query variable or endpoint argument (e.g. `page.offset`) used for the offset
+    pub offset_var: String,
+    /// Query variable or endpoint argument (e.g. `page.first`) used for the page size
+    pub limit_var: String,
+    /// Variables bound to the offset and limit
+    pub bound_offset_var: String,
+    pub bound_limit_var: String,
+    /// Arguments that need to be added to the query
+    pub injected: Vec<InjectedArgument>,
+}
+
+/// How the pages of a list are fetched
+#[derive(Default, Clone, Debug, PartialEq)]
+pub enum Pagination {
+    /// The whole list is fetched with a single request
+    #[default]
+    None,
+    Relay(RelayPagination),
+    Offset(OffsetPagination),
+}
+
+impl Pagination {
+    /// Parses the `paginate` module option, e.g. `paginate=relay(page_size=50)` or
+    /// `paginate=offset(page_size=500, offset_var=offset, limit_var=first)`
+    pub fn from_option(value: &str) -> Result<Pagination> {
+        let (mode, options) = parse_mode(value)?;
+        let mut page_size = DEFAULT_PAGE_SIZE;
+        let mut offset_var = "offset".to_owned();
+        let mut limit_var = "first".to_owned();
+        for (key, value) in options {
+            match (mode, key) {
+                ("relay" | "offset", "page_size") => page_size = parse_page_size(value)?,
+                ("offset", "offset_var") => offset_var = value.to_owned(),
+                ("offset", "limit_var") => limit_var = value.to_owned(),
+                _ => {
+                    return Err(Error::ModuleError(format!(
+                        "unknown {mode} pagination option `{key}`"
+                    )))
+                }
+            }
+        }
+        match mode {
+            "none" => Ok(Pagination::None),
+            "relay" => Ok(Pagination::Relay(RelayPagination {
+                page_size,
+                connection_path: vec![],
+                first_var: None,
+                after_var: String::new(),
+                injected: vec![],
+                page_info_results: vec![],
+            })),
+            "offset" => Ok(Pagination::Offset(OffsetPagination {
+                page_size,
+                offset_var,
+                limit_var,
+                bound_offset_var: String::new(),
+                bound_limit_var: String::new(),
+                injected: vec![],
+            })),
+            _ => Err(Error::ModuleError(format!(
+                "unknown pagination mode `{mode}`"
+            ))),
+        }
+    }
+
+    /// Resolves the pagination details from the query
+    pub fn resolve(&mut self, query: &str, details: &QueryDetails) -> Result<()> {
+        match self {
+            Pagination::None => Ok(()),
+            Pagination::Relay(relay) => relay.resolve(query, details),
+            Pagination::Offset(offset) => offset.resolve(query, details),
+        }
+    }
+
+    /// Arguments that need to be added to the query
+    pub fn injected_arguments(&self) -> &[InjectedArgument] {
+        match self {
+            Pagination::None => &[],
+            Pagination::Relay(relay) => &relay.injected,
+            Pagination::Offset(offset) => &offset.injected,
+        }
+    }
+
+    /// Bit mask of the result columns that must always be requested
+    pub fn required_columns(&self) -> u64 {
+        match self {
+            Pagination::None | Pagination::Offset(_) => 0,
+            Pagination::Relay(relay) => relay
+                .page_info_results
+                .iter()
+                .fold(0, |mask, i| mask | 1 << (*i).min(63)),
+        }
+    }
+
+    /// Number of list items per request, None if the list is fetched at once
+    pub fn page_size(&self) -> Option<i64> {
+        match self {
+            Pagination::None => None,
+            Pagination::Relay(relay) => Some(relay.page_size),
+            Pagination::Offset(offset) => Some(offset.page_size),
+        }
+    }
+
+    /// True if the SQL `OFFSET` is passed on to the server instead of skipping the rows
+    pub fn handles_offset(&self) -> bool {
+        matches!(self, Pagination::Offset(_))
+    }
+
+    /// Sets the variables for the first page.
+    /// `limit` is the number of list items that are needed, pages are not larger than that.
+    /// `skip` is the SQL `OFFSET`, it is only used if `handles_offset` is true.
+    pub fn first_page(
+        &self,
+        variables: &mut Map<String, Value>,
+        limit: Option<i64>,
+        skip: Option<i64>,
+    ) {
+        // values provided by the user take precedence, a limit only makes them smaller
+        let mut set_default = |variable: &str, value: i64, limit: Option<i64>| {
+            let value = variables
+                .get(variable)
+                .and_then(Value::as_i64)
+                .unwrap_or(value);
+            let value = limit.map_or(value, |limit| value.min(limit));
+            variables.insert(variable.to_owned(), Value::from(value));
+        };
+        match self {
+            Pagination::None => {}
+            Pagination::Relay(relay) => {
+                if let Some(first_var) = &relay.first_var {
+                    set_default(first_var, relay.page_size, limit);
+                }
+            }
+            Pagination::Offset(offset) => {
+                set_default(&offset.bound_limit_var, offset.page_size, limit);
+                let start = variables
+                    .get(&offset.bound_offset_var)
+                    .and_then(Value::as_i64)
+                    .unwrap_or(0);
+                variables.insert(
+                    offset.bound_offset_var.clone(),
+                    Value::from(start + skip.unwrap_or(0)),
+                );
+            }
+        }
+    }
+
+    /// Updates the variables for the next page.
+    /// `item_count` is the number of list items in the current page.
+    /// Returns false if there are no more pages.
+    pub fn next_page(
+        &self,
+        operation_result: &Value,
+        item_count: usize,
+        variables: &mut Map<String, Value>,
+    ) -> bool {
+        match self {
+            Pagination::None => false,
+            Pagination::Relay(relay) => {
+                let mut page_info = operation_result;
+                for segment in &relay.connection_path {
+                    page_info = page_info.get(segment).unwrap_or(&Value::Null);
+                }
+                let page_info = page_info.get("pageInfo").unwrap_or(&Value::Null);
+                let has_next_page = page_info
+                    .get("hasNextPage")
+                    .and_then(Value::as_bool)
+                    .unwrap_or(false);
+                let end_cursor = page_info.get("endCursor").unwrap_or(&Value::Null);
+                if !has_next_page || end_cursor.is_null() {
+                    return false;
+                }
+                variables.insert(relay.after_var.clone(), end_cursor.clone());
+                true
+            }
+            Pagination::Offset(offset) => {
+                let get = |variable: &str| variables.get(variable).and_then(Value::as_i64);
+                let limit = get(&offset.bound_limit_var).unwrap_or(offset.page_size);
+                // a short page is the last page
+                if limit <= 0 || (item_count as i64) < limit {
+                    return false;
+                }
+                let next_offset = get(&offset.bound_offset_var).unwrap_or(0) + limit;
+                variables.insert(offset.bound_offset_var.clone(), Value::from(next_offset));
+                true
+            }
+        }
+    }
+}
+
+impl RelayPagination {
+    fn resolve(&mut self, query: &str, details: &QueryDetails) -> Result<()> {
+        let Some(connection_path) = details.results.iter().find_map(|result| {
+            let index = result.fields.iter().position(|it| it == "pageInfo")?;
+            Some(result.path[..index].to_vec())
+        }) else {
+            return Err(Error::ModuleError(
+                "`paginate=relay` requires a `pageInfo { hasNextPage endCursor }` selection"
+                    .to_owned(),
+            ));
+        };
+        self.page_info_results.clear();
+        for field in ["hasNextPage", "endCursor"] {
+            let Some(index) = details.results.iter().position(|result| {
+                result.path.len() == connection_path.len() + 2
+                    && result.path.starts_with(&connection_path)
+                    && result.fields[connection_path.len()] == "pageInfo"
+                    && result.fields[connection_path.len() + 1] == field
+            }) else {
+                return Err(Error::ModuleError(format!(
+                    "`paginate=relay` requires `pageInfo.{field}` to be selected"
+                )));
+            };
+            self.page_info_results.push(index);
+        }
+
+        self.injected.clear();
+        self.first_var = bind_argument(
+            query,
+            &connection_path,
+            vec!["first".to_owned()],
+            "_apisql_first",
+            "Int",
+            &mut self.injected,
+        )?;
+        let Some(after_var) = bind_argument(
+            query,
+            &connection_path,
+            vec!["after".to_owned()],
+            "_apisql_after",
+            "String",
+            &mut self.injected,
+        )?
+        else {
+            return Err(Error::ModuleError(
+                "`paginate=relay` requires the `after` argument to be a variable".to_owned(),
+            ));
+        };
+        self.after_var = after_var;
+        self.connection_path = connection_path;
+        Ok(())
+    }
+}
+
+impl OffsetPagination {
+    fn resolve(&mut self, query: &str, details: &QueryDetails) -> Result<()> {
+        self.injected.clear();
+        self.bound_offset_var = bind_variable(
+            query,
+            details,
+            &self.offset_var,
+            "_apisql_offset",
+            "paginate=offset",
+            &mut self.injected,
+        )?;
+        self.bound_limit_var = bind_variable(
+            query,
+            details,
+            &self.limit_var,
+            "_apisql_limit",
+            "paginate=offset",
+            &mut self.injected,
+        )?;
+        Ok(())
+    }
+}
+
+/// Query variable that receives the SQL `LIMIT` or `OFFSET`
+#[derive(Clone, Debug, PartialEq)]
+pub struct BoundArgument {
+    pub variable: String,
+    /// Argument that needs to be added to the query when the variable is set
+    pub injected: Option<InjectedArgument>,
+}
+
+impl BoundArgument {
+    fn resolve(
+        query: &str,
+        details: &QueryDetails,
+        name: &str,
+        variable: &str,
+        option: &str,
+    ) -> Result<BoundArgument> {
+        let mut injected = vec![];
+        let variable = bind_variable(query, details, name, variable, option, &mut injected)?;
+        Ok(BoundArgument {
+            variable,
+            injected: injected.pop(),
+        })
+    }
+
+    fn set(
+        &self,
+        variables: &mut Map<String, Value>,
+        value: i64,
+        injected: &mut Vec<InjectedArgument>,
+    ) {
+        variables.insert(self.variable.clone(), Value::from(value));
+        injected.extend(self.injected.clone());
+    }
+}
+
+/// SQL `LIMIT` and `OFFSET` passed on to endpoint arguments without pagination, see the
+/// `limit_arg` and `offset_arg` module options
+#[derive(Default, Clone, Debug, PartialEq)]
+pub struct LimitArguments {
+    /// Query variable or endpoint argument (e.g. `first` or `page.first`)
+    pub limit_arg: Option<String>,
+    pub offset_arg: Option<String>,
+    pub limit: Option<BoundArgument>,
+    pub offset: Option<BoundArgument>,
+}
+
+impl LimitArguments {
+    pub fn resolve(&mut self, query: &str, details: &QueryDetails) -> Result<()> {
+        self.limit = self
+            .limit_arg
+            .as_deref()
+            .map(|name| BoundArgument::resolve(query, details, name, "_apisql_limit", "limit_arg"))
+            .transpose()?;
+        self.offset = self
+            .offset_arg
+            .as_deref()
+            .map(|name| {
+                BoundArgument::resolve(query, details, name, "_apisql_offset", "offset_arg")
+            })
+            .transpose()?;
+        Ok(())
+    }
+
+    /// Sets the variables for `LIMIT limit OFFSET skip` and adds the arguments that need to be
+    /// injected for them. Without an `offset_arg` the skipped rows are included in the limit.
+    pub fn apply(
+        &self,
+        variables: &mut Map<String, Value>,
+        limit: Option<i64>,
+        skip: Option<i64>,
+        injected: &mut Vec<InjectedArgument>,
+    ) {
+        let skip = skip.unwrap_or(0);
+        let passed_skip = match &self.offset {
+            Some(offset) if skip > 0 => {
+                offset.set(variables, skip, injected);
+                skip
+            }
+            _ => 0,
+        };
+        if let (Some(bound), Some(limit)) = (&self.limit, limit) {
+            let mut limit = limit + skip - passed_skip;
+            // a smaller value provided by the user is kept
+            if let Some(value) = variables.get(&bound.variable).and_then(Value::as_i64) {
+                limit = limit.min(value);
+            }
+            bound.set(variables, limit, injected);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::{json, Map};
+
+    use crate::{optimize_query::optimize_query, parse_query::parse};
+
+    use super::Pagination;
+
+    #[test]
+    fn test_relay_pagination() {
+        let query = r#"query Orders($after: String) {
+          orders(after: $after) {
+            edges { node { id } }
+            pageInfo { hasNextPage endCursor }
+          }
+        }"#;
+        let details = parse(query).unwrap();
+        let mut pagination = Pagination::from_option("relay(page_size=2)").unwrap();
+        pagination.resolve(query, &details).unwrap();
+
+        // pageInfo is requested even if the columns are not used
+        assert_eq!(pagination.required_columns(), 0b110);
+        let request = optimize_query(
+            query,
+            pagination.required_columns(),
+            &[],
+            pagination.injected_arguments(),
+        )
+        .unwrap();
+        assert!(request.contains("query Orders($after: String, $_apisql_first: Int)"));
+        assert!(request.contains("orders(after: $after, first: $_apisql_first)"));
+
+        let mut variables = Map::new();
+        pagination.first_page(&mut variables, None, None);
+        assert_eq!(variables.get("_apisql_first"), Some(&json!(2)));
+
+        let result = json!({"edges": [], "pageInfo": {"hasNextPage": true, "endCursor": "c2"}});
+        assert!(pagination.next_page(&result, 2, &mut variables));
+        assert_eq!(variables.get("after"), Some(&json!("c2")));
+
+        let result = json!({"edges": [], "pageInfo": {"hasNextPage": false, "endCursor": "c4"}});
+        assert!(!pagination.next_page(&result, 2, &mut variables));
+    }
+
+    #[test]
+    fn test_offset_pagination() {
+        let query = r#"query Invoices($storeId: String!) {
+          invoices(storeId: $storeId) { nodes { id } totalCount }
+        }"#;
+        let details = parse(query).unwrap();
+        let mut pagination = Pagination::from_option(
+            "offset(page_size=500, offset_var=page.offset, limit_var=page.first)",
+        )
+        .unwrap();
+        pagination.resolve(query, &details).unwrap();
+
+        let request =
+            optimize_query(query, u64::MAX, &[], pagination.injected_arguments()).unwrap();
+        assert!(request.contains(
+            "query Invoices($storeId: String!, $_apisql_offset: Int, $_apisql_limit: Int)"
+        ));
+        assert!(request.contains(
+            "invoices(storeId: $storeId, page: {first: $_apisql_limit, offset: $_apisql_offset})"
+        ));
+
+        let mut variables = Map::new();
+        pagination.first_page(&mut variables, None, None);
+        assert_eq!(variables.get("_apisql_offset"), Some(&json!(0)));
+        assert_eq!(variables.get("_apisql_limit"), Some(&json!(500)));
+        assert!(pagination.next_page(&json!({}), 500, &mut variables));
+        assert_eq!(variables.get("_apisql_offset"), Some(&json!(500)));
+        assert!(!pagination.next_page(&json!({}), 499, &mut variables));
+
+        // existing query variables are used as they are
+        let query = r#"query Invoices($offset: Int, $first: Int) {
+          invoices(offset: $offset, first: $first) { id }
+        }"#;
+        let details = parse(query).unwrap();
+        let mut pagination = Pagination::from_option("offset").unwrap();
+        pagination.resolve(query, &details).unwrap();
+        assert!(pagination.injected_arguments().is_empty());
+        let mut variables = Map::new();
+        variables.insert("first".to_owned(), json!(10));
+        pagination.first_page(&mut variables, None, None);
+        assert!(pagination.next_page(&json!({}), 10, &mut variables));
+        assert_eq!(variables.get("offset"), Some(&json!(10)));
+    }
+
+    #[test]
+    fn test_relay_requires_page_info() {
+        let query = "query Orders { orders { edges { node { id } } } }";
+        let details = parse(query).unwrap();
+        let mut pagination = Pagination::from_option("relay").unwrap();
+        assert!(pagination.resolve(query, &details).is_err());
+    }
+}
@@ -1,16 +1,222 @@
+use std::collections::HashMap;
+use std::fmt;
+
 use graphql_parser::query::{
-    parse_query, Definition, Document, OperationDefinition, Query, Selection, SelectionSet,
+    parse_query, Definition, Document, Field, FragmentDefinition, InlineFragment,
+    OperationDefinition, Selection, SelectionSet, Type, TypeCondition, Value as GraphqlValue,
 };
+use rusqlite::vtab::parse_boolean;
+use serde_json::Value;
 
-#[derive(Default, Clone)]
+/// Type of a query variable, e.g. `[Int!]!`
+#[derive(Clone, Debug, PartialEq)]
+pub enum VariableType {
+    Named(String),
+    List(Box<VariableType>),
+    NonNull(Box<VariableType>),
+}
+
+impl<'a> From<&Type<'a, &'a str>> for VariableType {
+    fn from(value: &Type<'a, &'a str>) -> Self {
+        match value {
+            Type::NamedType(name) => VariableType::Named(name.to_string()),
+            Type::ListType(inner) => VariableType::List(Box::new(inner.as_ref().into())),
+            Type::NonNullType(inner) => VariableType::NonNull(Box::new(inner.as_ref().into())),
+        }
+    }
+}
+
+impl fmt::Display for VariableType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VariableType::Named(name) => write!(f, "{name}"),
+            VariableType::List(inner) => write!(f, "[{inner}]"),
+            VariableType::NonNull(inner) => write!(f, "{inner}!"),
+        }
+    }
+}
+
+impl VariableType {
+    pub fn is_list(&self) -> bool {
+        match self {
+            VariableType::Named(_) => false,
+            VariableType::List(_) => true,
+            VariableType::NonNull(inner) => inner.is_list(),
+        }
+    }
+
+    /// Type of the column exposing the variable
+    pub fn column_type(&self) -> ColumnType {
+        match self {
+            VariableType::Named(name) => ColumnType::from_graphql_type(name),
+            VariableType::List(_) => ColumnType::Any,
+            VariableType::NonNull(inner) => inner.column_type(),
+        }
+    }
+
+    /// Coerces a value, e.g. a value from a SQL constraint, to a valid input value of this type
+    pub fn coerce(&self, value: Value) -> anyhow::Result<Value> {
+        let inner = match self {
+            VariableType::NonNull(inner) => {
+                if value.is_null() {
+                    return Err(anyhow::Error::msg(format!(
+                        "null value for non-null type {self}"
+                    )));
+                }
+                inner.as_ref()
+            }
+            _ if value.is_null() => return Ok(Value::Null),
+            _ => self,
+        };
+        match inner {
+            VariableType::NonNull(_) => inner.coerce(value),
+            VariableType::List(item_type) => match decode_json(value) {
+                Value::Array(items) => Ok(Value::Array(
+                    items
+                        .into_iter()
+                        .map(|item| item_type.coerce(item))
+                        .collect::<anyhow::Result<_>>()?,
+                )),
+                // a single value is accepted as a list of one item
+                value => Ok(Value::Array(vec![item_type.coerce(value)?])),
+            },
+            VariableType::Named(name) => coerce_named(name, value),
+        }
+    }
+}
+
+/// Decodes JSON encoded lists and objects, other values are returned as they are
+fn decode_json(value: Value) -> Value {
+    let Value::String(text) = &value else {
+        return value;
+    };
+    let trimmed = text.trim_start();
+    if !trimmed.starts_with('[') && !trimmed.starts_with('{') {
+        return value;
+    }
+    serde_json::from_str(text).unwrap_or(value)
+}
+
+fn coerce_named(type_name: &str, value: Value) -> anyhow::Result<Value> {
+    let invalid =
+        |value: &Value| anyhow::Error::msg(format!("invalid value for type {type_name}: {value}"));
+    let coerced = match (type_name, &value) {
+        // GraphQL `Int` is a signed 32-bit integer
+        ("Int", Value::Number(number)) => match (number.as_i64(), number.as_f64()) {
+            (Some(int), _) => Value::from(i32::try_from(int).map_err(|_| invalid(&value))?),
+            (None, Some(float))
+                if number.is_f64()
+                    && float.fract() == 0.0
+                    && (i32::MIN as f64..=i32::MAX as f64).contains(&float) =>
+            {
+                Value::from(float as i32)
+            }
+            _ => return Err(invalid(&value)),
+        },
+        ("Int", Value::String(text)) => {
+            Value::from(text.trim().parse::<i32>().map_err(|_| invalid(&value))?)
+        }
+        ("Float", Value::Number(_)) => value,
+        ("Float", Value::String(text)) => {
+            Value::from(text.trim().parse::<f64>().map_err(|_| invalid(&value))?)
+        }
+        ("Boolean", Value::Bool(_)) => value,
+        ("Boolean", Value::Number(number)) => Value::Bool(number.as_f64() != Some(0.0)),
+        ("Boolean", Value::String(text)) => {
+            Value::Bool(parse_boolean(text.trim()).ok_or_else(|| invalid(&value))?)
+        }
+        ("String" | "ID", Value::String(_)) => value,
+        ("String" | "ID", Value::Number(number)) => Value::String(number.to_string()),
+        ("Int" | "Float" | "Boolean" | "String" | "ID", _) => return Err(invalid(&value)),
+        // Enums, input objects and custom scalars: JSON encoded input objects are decoded,
+        // everything else is passed on to the server as it is
+        _ => decode_json(value),
+    };
+    Ok(coerced)
+}
+
+/// Converts a constant GraphQL value, e.g. a variable default value, to JSON
+fn to_json_value<'a>(value: &GraphqlValue<'a, &'a str>) -> Value {
+    match value {
+        GraphqlValue::Variable(name) => Value::String(format!("${name}")),
+        GraphqlValue::Int(number) => Value::from(number.as_i64()),
+        GraphqlValue::Float(number) => Value::from(*number),
+        GraphqlValue::String(text) => Value::String(text.clone()),
+        GraphqlValue::Boolean(value) => Value::Bool(*value),
+        GraphqlValue::Null => Value::Null,
+        GraphqlValue::Enum(name) => Value::String(name.to_string()),
+        GraphqlValue::List(items) => Value::Array(items.iter().map(to_json_value).collect()),
+        GraphqlValue::Object(fields) => Value::Object(
+            fields
+                .iter()
+                .map(|(key, value)| (key.to_string(), to_json_value(value)))
+                .collect(),
+        ),
+    }
+}
+
+#[derive(Clone)]
 pub struct Variable {
     pub name: String,
+    pub var_type: VariableType,
+    pub default_value: Option<Value>,
+}
+
+impl Variable {
+    /// A variable is required if it is non-null and has no default value
+    pub fn is_required(&self) -> bool {
+        matches!(self.var_type, VariableType::NonNull(_)) && self.default_value.is_none()
+    }
+}
+
+/// SQLite type affinity declared for a column
+#[derive(Default, Clone, Copy, PartialEq, Debug)]
+pub enum ColumnType {
+    /// No declared type, values keep the type they have in the response
+    #[default]
+    Any,
+    Integer,
+    Real,
+    Text,
+}
+
+impl ColumnType {
+    /// Maps a GraphQL scalar type name to a column type
+    pub fn from_graphql_type(type_name: &str) -> ColumnType {
+        match type_name {
+            "Int" | "Boolean" => ColumnType::Integer,
+            "Float" => ColumnType::Real,
+            "String" | "ID" => ColumnType::Text,
+            _ => ColumnType::Any,
+        }
+    }
+
+    /// Type name as used in the `CREATE TABLE` statement
+    pub fn sql_type(&self) -> &'static str {
+        match self {
+            ColumnType::Any => "",
+            ColumnType::Integer => "INTEGER",
+            ColumnType::Real => "REAL",
+            ColumnType::Text => "TEXT",
+        }
+    }
 }
 
 /// Path relative to the query
 #[derive(Default, Clone)]
 pub struct ResultPath {
+    /// Response keys, the alias of an aliased field
     pub path: Vec<String>,
+    /// Field names of the path segments
+    pub fields: Vec<String>,
+    pub column_type: ColumnType,
+    /// Named GraphQL type of the field, only known after introspection
+    pub graphql_type: Option<String>,
+    /// Type condition of the enclosing inline fragment, e.g. `AuthToken`. The first fragment
+    /// if several fragments select the path
+    pub type_condition: Option<String>,
+    /// The selection below the path is a single column of serialized JSON, see `json_columns`
+    pub json: bool,
 }
 
 impl ResultPath {
@@ -22,95 +228,428 @@ impl ResultPath {
 #[derive(Default, Clone)]
 pub struct QueryDetails {
     pub operation_name: String,
+    /// Response key of the endpoint
     pub endpoint_name: String,
+    /// Field name of the endpoint, differs from `endpoint_name` if it is aliased
+    pub endpoint_field: String,
     pub variables: Vec<Variable>,
     pub results: Vec<ResultPath>,
 }
 
+impl QueryDetails {
+    /// Replaces the results below each of the `json_columns` paths with a single JSON column at
+    /// the position of the first one
+    pub fn set_json_columns(&mut self, json_columns: &[Vec<String>]) -> anyhow::Result<()> {
+        for json_column in json_columns {
+            let Some(first) = self
+                .results
+                .iter()
+                .position(|it| it.path.starts_with(json_column))
+            else {
+                return Err(anyhow::Error::msg(format!(
+                    "`json_columns` path `{}` isn't selected",
+                    json_column.join(".")
+                )));
+            };
+            let fields = self.results[first].fields[..json_column.len()].to_vec();
+            self.results[first] = ResultPath {
+                path: json_column.clone(),
+                fields,
+                column_type: ColumnType::Text,
+                json: true,
+                ..ResultPath::default()
+            };
+            let mut i = 0;
+            self.results.retain(|it| {
+                i += 1;
+                i - 1 == first || !it.path.starts_with(json_column)
+            });
+        }
+        Ok(())
+    }
+}
+
 pub fn parse(full_query: &str) -> anyhow::Result<QueryDetails> {
-    let mut result = parse_query(full_query)?;
-    let (op, operation_name, variables) = parse_query_variable(&mut result)?;
-    let (endpoint_name, results) = parse_query_results(&op)?;
+    parse_operation(full_query, false)
+}
+
+/// Parses a mutation document like `parse` parses a query
+pub fn parse_mutation(full_query: &str) -> anyhow::Result<QueryDetails> {
+    parse_operation(full_query, true)
+}
+
+/// Parses a document and replaces named fragment spreads like `...OrderFields` with inline
+/// fragments, so that operations can be processed without the fragment definitions
+pub fn parse_document<'a>(query: &'a str) -> anyhow::Result<Document<'a, &'a str>> {
+    let mut document = parse_query(query)?;
+    let mut fragments = HashMap::new();
+    document.definitions.retain(|it| match it {
+        Definition::Fragment(fragment) => {
+            fragments.insert(fragment.name, fragment.clone());
+            false
+        }
+        Definition::Operation(_) => true,
+    });
+    for definition in &mut document.definitions {
+        let Definition::Operation(op) = definition else {
+            continue;
+        };
+        // the result of a mutation tells whether it returned an error type
+        let (selection_set, min_type_conditions) = match op {
+            OperationDefinition::SelectionSet(selection_set) => (selection_set, 2),
+            OperationDefinition::Query(query) => (&mut query.selection_set, 2),
+            OperationDefinition::Mutation(mutation) => (&mut mutation.selection_set, 1),
+            OperationDefinition::Subscription(subscription) => (&mut subscription.selection_set, 2),
+        };
+        inline_fragments(selection_set, &fragments, &mut vec![])?;
+        select_typename(selection_set, min_type_conditions);
+    }
+    Ok(document)
+}
+
+/// Selects `__typename` next to inline fragments on at least `min_type_conditions` different
+/// types, the column tells which fragment applies to a row
+fn select_typename<'a>(selection_set: &mut SelectionSet<'a, &'a str>, min_type_conditions: usize) {
+    let mut has_typename = false;
+    let mut type_conditions = vec![];
+    for item in &mut selection_set.items {
+        match item {
+            Selection::Field(field) => {
+                has_typename |= field.alias.unwrap_or(field.name) == "__typename";
+                select_typename(&mut field.selection_set, min_type_conditions);
+            }
+            Selection::InlineFragment(inline_fragment) => {
+                if let Some(TypeCondition::On(type_name)) = inline_fragment.type_condition {
+                    if !type_conditions.contains(&type_name) {
+                        type_conditions.push(type_name);
+                    }
+                }
+                select_typename(&mut inline_fragment.selection_set, min_type_conditions);
+            }
+            Selection::FragmentSpread(_) => {}
+        }
+    }
+    if type_conditions.len() >= min_type_conditions.max(1) && !has_typename {
+        selection_set.items.insert(
+            0,
+            Selection::Field(Field {
+                position: selection_set.span.0,
+                alias: None,
+                name: "__typename",
+                arguments: vec![],
+                directives: vec![],
+                selection_set: SelectionSet {
+                    span: selection_set.span,
+                    items: vec![],
+                },
+            }),
+        );
+    }
+}
+
+/// `spreads` are the fragments that are being inlined, a fragment must not spread itself
+fn inline_fragments<'a>(
+    selection_set: &mut SelectionSet<'a, &'a str>,
+    fragments: &HashMap<&'a str, FragmentDefinition<'a, &'a str>>,
+    spreads: &mut Vec<&'a str>,
+) -> anyhow::Result<()> {
+    for item in &mut selection_set.items {
+        match item {
+            Selection::Field(field) => {
+                inline_fragments(&mut field.selection_set, fragments, spreads)?
+            }
+            Selection::InlineFragment(inline_fragment) => {
+                inline_fragments(&mut inline_fragment.selection_set, fragments, spreads)?
+            }
+            Selection::FragmentSpread(spread) => {
+                let name = spread.fragment_name;
+                let Some(fragment) = fragments.get(name) else {
+                    return Err(anyhow::Error::msg(format!("Unknown fragment `{name}`")));
+                };
+                if spreads.contains(&name) {
+                    return Err(anyhow::Error::msg(format!(
+                        "Fragment `{name}` spreads itself"
+                    )));
+                }
+                let mut selection_set = fragment.selection_set.clone();
+                spreads.push(name);
+                inline_fragments(&mut selection_set, fragments, spreads)?;
+                spreads.pop();
+                *item = Selection::InlineFragment(InlineFragment {
+                    position: spread.position,
+                    type_condition: Some(fragment.type_condition.clone()),
+                    directives: spread.directives.clone(),
+                    selection_set,
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+fn parse_operation(full_query: &str, mutation: bool) -> anyhow::Result<QueryDetails> {
+    let result = parse_document(full_query)?;
+    let (selection_set, operation_name, variables) = parse_query_variable(&result, mutation)?;
+    let (endpoint_name, endpoint_field, results) = parse_query_results(selection_set)?;
     Ok(QueryDetails {
         operation_name,
         endpoint_name,
+        endpoint_field,
         variables,
         results,
     })
 }
 
+/// Returns the selection set, the name and the variables of the query (or mutation) operation
 pub fn parse_query_variable<'a>(
     full_query: &'a Document<'a, &'a str>,
-) -> anyhow::Result<(&'a Query<'a, &'a str>, String, Vec<Variable>)> {
-    let Some(Definition::Operation(OperationDefinition::Query(query_op))) =
-        full_query.definitions.iter().find(|it| match it {
-            Definition::Operation(op) => match op {
-                OperationDefinition::Query(_) => true,
-                _ => false,
-            },
-            Definition::Fragment(_) => false,
-        })
-    else {
-        return Err(anyhow::Error::msg("No query operation found"));
+    mutation: bool,
+) -> anyhow::Result<(&'a SelectionSet<'a, &'a str>, String, Vec<Variable>)> {
+    let operation = full_query.definitions.iter().find_map(|it| match it {
+        Definition::Operation(OperationDefinition::Query(op)) if !mutation => {
+            Some((op.name, &op.variable_definitions, &op.selection_set))
+        }
+        Definition::Operation(OperationDefinition::Mutation(op)) if mutation => {
+            Some((op.name, &op.variable_definitions, &op.selection_set))
+        }
+        _ => None,
+    });
+    let Some((name, variable_definitions, selection_set)) = operation else {
+        let kind = if mutation { "mutation" } else { "query" };
+        return Err(anyhow::Error::msg(format!("No {kind} operation found")));
     };
 
-    let Some(operation_name) = query_op.name.map(|it| it.to_string()) else {
+    let Some(operation_name) = name.map(|it| it.to_string()) else {
         return Err(anyhow::Error::msg("Missing operation name"));
     };
 
-    let vars = query_op
-        .variable_definitions
+    let vars = variable_definitions
         .iter()
         .map(|var| Variable {
             name: var.name.to_string(),
+            var_type: (&var.var_type).into(),
+            default_value: var.default_value.as_ref().map(to_json_value),
         })
         .collect();
-    Ok((query_op, operation_name.to_string(), vars))
+    Ok((selection_set, operation_name, vars))
 }
 
+/// `base` holds the response keys and `base_fields` the field names of the parent fields
 fn collect_fields<'a>(
     selection_set: &SelectionSet<'a, &'a str>,
     base: &Vec<String>,
+    base_fields: &Vec<String>,
 ) -> anyhow::Result<Vec<ResultPath>> {
     let mut out = Vec::new();
     for item in &selection_set.items {
         match item {
             Selection::Field(field) => {
+                // the server returns an aliased field under its alias
                 let mut path = base.clone();
-                path.push(field.name.to_string());
+                path.push(field.alias.unwrap_or(field.name).to_string());
+                let mut fields = base_fields.clone();
+                fields.push(field.name.to_string());
                 if field.selection_set.items.is_empty() {
-                    out.push(ResultPath { path });
+                    out.push(ResultPath {
+                        path,
+                        fields,
+                        column_type: ColumnType::Any,
+                        graphql_type: None,
+                        type_condition: None,
+                        json: false,
+                    });
                 } else {
-                    out.append(&mut collect_fields(&field.selection_set, &path)?);
+                    out.append(&mut collect_fields(&field.selection_set, &path, &fields)?);
                 }
             }
             Selection::InlineFragment(inline_fragment) => {
                 // e.g. "... on AuthToken"
-                let mut paths = collect_fields(&inline_fragment.selection_set, base)?;
+                let mut paths = collect_fields(&inline_fragment.selection_set, base, base_fields)?;
+                if let Some(TypeCondition::On(type_name)) = &inline_fragment.type_condition {
+                    for path in paths.iter_mut().filter(|it| it.type_condition.is_none()) {
+                        path.type_condition = Some(type_name.to_string());
+                    }
+                }
                 out.append(&mut paths);
             }
-            Selection::FragmentSpread(_) => {
-                return Err(anyhow::Error::msg(format!("FragmentSpread not supported")));
+            Selection::FragmentSpread(spread) => {
+                return Err(anyhow::Error::msg(format!(
+                    "Fragment `{}` not inlined",
+                    spread.fragment_name
+                )));
             }
         };
     }
     Ok(out)
 }
 
-fn parse_query_results<'a>(op: &Query<'a, &'a str>) -> anyhow::Result<(String, Vec<ResultPath>)> {
-    if op.selection_set.items.len() != 1 {
-        return Err(anyhow::Error::msg(format!(
-            "Exactly one operation expected"
-        )));
+fn parse_query_results<'a>(
+    selection_set: &SelectionSet<'a, &'a str>,
+) -> anyhow::Result<(String, String, Vec<ResultPath>)> {
+    if selection_set.items.len() != 1 {
+        return Err(anyhow::Error::msg(
+            "Exactly one operation expected".to_string(),
+        ));
     }
 
     // get operation name
-    let item = op.selection_set.items.first().unwrap();
+    let item = selection_set.items.first().unwrap();
     let Selection::Field(field) = item else {
         return Err(anyhow::Error::msg(format!("Field expected ({:?})", item)));
     };
-    let operation_name = field.name.to_string();
+    let endpoint_name = field.alias.unwrap_or(field.name).to_string();
+
+    // a path that is selected by several inline fragments is a single column
+    let mut out: Vec<ResultPath> = vec![];
+    for result in collect_fields(&field.selection_set, &vec![], &vec![])? {
+        if !out.iter().any(|it| it.path == result.path) {
+            out.push(result);
+        }
+    }
+    Ok((endpoint_name, field.name.to_string(), out))
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use crate::optimize_query::optimize_query;
+
+    use super::{parse, ColumnType};
+
+    #[test]
+    fn test_coerce_variables() {
+        let details = parse(
+            r#"query Orders($first: Int!, $price: Float, $active: Boolean, $id: ID,
+                $ids: [Int!], $filter: OrderFilter, $status: Status) {
+              orders(first: $first) { id }
+            }"#,
+        )
+        .unwrap();
+        let coerce =
+            |i: usize, value: serde_json::Value| details.variables[i].var_type.coerce(value);
+        assert_eq!(coerce(0, json!(10)).unwrap(), json!(10));
+        assert_eq!(coerce(0, json!("10")).unwrap(), json!(10));
+        assert_eq!(coerce(0, json!(10.0)).unwrap(), json!(10));
+        assert!(coerce(0, json!(10.5)).is_err());
+        // out of the 32-bit range instead of saturating
+        assert!(coerce(0, json!(1e30)).is_err());
+        assert!(coerce(0, json!(2_147_483_648_i64)).is_err());
+        assert!(coerce(0, json!("-2147483649")).is_err());
+        assert_eq!(coerce(0, json!(-2147483648.0)).unwrap(), json!(-2147483648));
+        assert!(coerce(0, json!(null)).is_err());
+        assert_eq!(coerce(1, json!(2)).unwrap(), json!(2));
+        assert_eq!(coerce(1, json!("2.5")).unwrap(), json!(2.5));
+        assert_eq!(coerce(1, json!(null)).unwrap(), json!(null));
+        assert_eq!(coerce(2, json!(1)).unwrap(), json!(true));
+        assert_eq!(coerce(2, json!("false")).unwrap(), json!(false));
+        assert_eq!(coerce(3, json!(5)).unwrap(), json!("5"));
+        assert_eq!(coerce(4, json!("[1, 2]")).unwrap(), json!([1, 2]));
+        assert_eq!(coerce(4, json!(3)).unwrap(), json!([3]));
+        assert!(coerce(4, json!("[1, null]")).is_err());
+        assert_eq!(
+            coerce(5, json!(r#"{"status": "OPEN"}"#)).unwrap(),
+            json!({"status": "OPEN"})
+        );
+        assert_eq!(coerce(6, json!("OPEN")).unwrap(), json!("OPEN"));
+        assert_eq!(
+            details.variables[4].var_type.to_string(),
+            "[Int!]".to_owned()
+        );
+    }
 
-    let out = collect_fields(&field.selection_set, &vec![])?;
-    Ok((operation_name, out))
+    #[test]
+    fn test_variable_defaults() {
+        let details = parse(
+            r#"query Orders($first: Int! = 10, $after: String!, $sort: [Sort!] = [{key: id, desc: true}]) {
+              orders(first: $first, after: $after, sort: $sort) { id }
+            }"#,
+        )
+        .unwrap();
+        let variables = &details.variables;
+        assert_eq!(variables[0].default_value, Some(json!(10)));
+        assert!(!variables[0].is_required());
+        assert_eq!(variables[1].default_value, None);
+        assert!(variables[1].is_required());
+        assert_eq!(
+            variables[2].default_value,
+            Some(json!([{"key": "id", "desc": true}]))
+        );
+        assert!(!variables[2].is_required());
+    }
+
+    #[test]
+    fn test_fragments() {
+        let query = r#"query Orders {
+          orders { ...OrderFields }
+        }
+        fragment OrderFields on Order { id customer { ...CustomerFields } }
+        fragment CustomerFields on Customer { name email }"#;
+        let details = parse(query).unwrap();
+        let columns: Vec<String> = details.results.iter().map(|it| it.to_string()).collect();
+        assert_eq!(columns, vec!["id", "customer_name", "customer_email"]);
+        assert_eq!(details.results[0].type_condition.as_deref(), Some("Order"));
+        assert_eq!(
+            details.results[1].type_condition.as_deref(),
+            Some("Customer")
+        );
+
+        // unused columns are pruned inside the fragments
+        let request = optimize_query(query, 0b101, &[], &[]).unwrap();
+        assert!(!request.contains("fragment"));
+        assert!(request.contains("... on Order"));
+        assert!(request.contains("email"));
+        assert!(!request.contains("name"));
+
+        let err = parse(
+            r#"query Nodes { nodes { ...A } }
+            fragment A on Node { id children { ...B } }
+            fragment B on Node { ...A }"#,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(err.to_string(), "Fragment `A` spreads itself");
+        assert!(parse("query Orders { orders { ...Missing } }").is_err());
+    }
+
+    #[test]
+    fn test_union_columns() {
+        let query = r#"query Documents {
+          documents {
+            ... on Invoice { id total }
+            ... on Requisition { id status }
+          }
+        }"#;
+        let details = parse(query).unwrap();
+        let columns: Vec<String> = details.results.iter().map(|it| it.to_string()).collect();
+        assert_eq!(columns, vec!["__typename", "id", "total", "status"]);
+
+        let request = optimize_query(query, 0b1001, &[], &[]).unwrap();
+        assert!(request.contains("__typename"));
+        assert!(request.contains("... on Requisition {\n      status\n    }"));
+        assert!(!request.contains("total"));
+    }
+
+    #[test]
+    fn test_json_columns() {
+        let query = r#"query Orders {
+          orders { id customer { name address { city } } tags total }
+        }"#;
+        let mut details = parse(query).unwrap();
+        let json_columns = vec![vec!["customer".to_owned()], vec!["tags".to_owned()]];
+        details.set_json_columns(&json_columns).unwrap();
+        let columns: Vec<String> = details.results.iter().map(|it| it.to_string()).collect();
+        assert_eq!(columns, vec!["id", "customer", "tags", "total"]);
+        assert!(details.results[1].json);
+        assert_eq!(details.results[1].column_type, ColumnType::Text);
+
+        // the whole selection of a used JSON column is kept
+        let request = optimize_query(query, 0b10, &json_columns, &[]).unwrap();
+        assert!(request.contains("city"));
+        assert!(!request.contains("tags"));
+
+        assert!(details
+            .set_json_columns(&[vec!["lines".to_owned()]])
+            .is_err());
+    }
 }
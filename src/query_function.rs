@@ -0,0 +1,264 @@
+use std::marker::PhantomData;
+use std::os::raw::c_int;
+
+use rusqlite::{
+    ffi,
+    vtab::{
+        eponymous_only_module, Context, Filters, IndexConstraintOp, IndexInfo, VTab,
+        VTabConnection, VTabCursor,
+    },
+    Connection, Error, Result,
+};
+use serde_json::Value;
+
+use crate::{
+    estimate::{Estimates, Plan},
+    fetch_function::fetch,
+    graphql::to_sql_value,
+};
+
+/// Register the eponymous `graphql_query` table-valued function for ad-hoc queries.
+/// ```sql
+/// SELECT * FROM graphql_query(:url, :query, :variables_json)
+/// ```
+/// Like `json_tree` it returns a row per node of the response `data`:
+/// `path` (e.g. `$.films[0].title`), `key` (object key or array index), `value` (JSON text for
+/// objects and arrays), `type` (null, true, false, integer, real, text, array or object) and
+/// `parent` (path of the parent node).
+/// `variables_json` is an optional JSON object with the query variables.
+pub fn load_module(conn: &Connection) -> Result<()> {
+    let aux: Option<()> = None;
+    conn.create_module(
+        "graphql_query",
+        eponymous_only_module::<QueryFunctionTab>(),
+        aux,
+    )
+}
+
+const URL_COL: usize = 5;
+const VARIABLES_COL: usize = 7;
+
+/// Cost of a plan without `url` or `query`
+const MISSING_ARGUMENT_COST: f64 = 1e30;
+
+#[repr(C)]
+struct QueryFunctionTab {
+    /// Base class. Must be first
+    base: ffi::sqlite3_vtab,
+}
+
+unsafe impl<'vtab> VTab<'vtab> for QueryFunctionTab {
+    type Aux = ();
+    type Cursor = QueryFunctionCursor<'vtab>;
+
+    fn connect(
+        _db: &mut VTabConnection,
+        _aux: Option<&()>,
+        _args: &[&[u8]],
+    ) -> Result<(String, QueryFunctionTab)> {
+        let sql = "CREATE TABLE x(path TEXT, key, value, type TEXT, parent TEXT, \
+                   url HIDDEN TEXT, query HIDDEN TEXT, variables HIDDEN TEXT);";
+        Ok((
+            sql.to_owned(),
+            QueryFunctionTab {
+                base: ffi::sqlite3_vtab::default(),
+            },
+        ))
+    }
+
+    fn best_index(&self, info: &mut IndexInfo) -> Result<()> {
+        // argv indices follow the order of the hidden columns, idx_num has a bit per argument
+        let mut arguments = [None; 3];
+        for (i, c) in info.constraints().enumerate() {
+            let col = c.column() as usize;
+            if c.is_usable()
+                && c.operator() == IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_EQ
+                && (URL_COL..=VARIABLES_COL).contains(&col)
+            {
+                arguments[col - URL_COL] = Some(i);
+            }
+        }
+        let mut idx_num = 0;
+        let mut argv_index = 0;
+        for (bit, constraint_idx) in arguments.iter().enumerate() {
+            let Some(constraint_idx) = constraint_idx else {
+                continue;
+            };
+            idx_num |= 1 << bit;
+            argv_index += 1;
+            let mut usage = info.constraint_usage(*constraint_idx);
+            usage.set_argv_index(argv_index);
+            usage.set_omit(true);
+        }
+        info.set_idx_num(idx_num);
+
+        let estimate = Estimates::default().estimate(&Plan::default());
+        if arguments[0].is_none() || arguments[1].is_none() {
+            info.set_estimated_cost(MISSING_ARGUMENT_COST);
+        } else {
+            info.set_estimated_cost(estimate.cost);
+        }
+        info.set_estimated_rows(estimate.rows as i64);
+        Ok(())
+    }
+
+    fn open(&mut self) -> Result<QueryFunctionCursor<'_>> {
+        Ok(QueryFunctionCursor {
+            base: ffi::sqlite3_vtab_cursor::default(),
+            data: Value::Null,
+            nodes: vec![],
+            arguments: [None, None, None],
+            row_number: 0,
+            phantom: PhantomData,
+        })
+    }
+}
+
+/// A node of the response data
+struct Node {
+    /// JSON pointer of the node in the response data
+    pointer: String,
+    path: String,
+    key: Value,
+    parent: Option<String>,
+}
+
+/// Adds `value` and its descendants in document order
+fn add_nodes(nodes: &mut Vec<Node>, value: &Value, pointer: String, path: String, key: Value) {
+    let parent = nodes.len();
+    nodes.push(Node {
+        pointer: pointer.clone(),
+        path: path.clone(),
+        key,
+        parent: None,
+    });
+    let children: Vec<(String, String, Value, &Value)> = match value {
+        Value::Object(object) => object
+            .iter()
+            .map(|(key, child)| {
+                let escaped = key.replace('~', "~0").replace('/', "~1");
+                let path_key = if key.chars().all(|c| c.is_alphanumeric() || c == '_') {
+                    key.clone()
+                } else {
+                    format!("\"{key}\"")
+                };
+                (
+                    format!("{pointer}/{escaped}"),
+                    format!("{path}.{path_key}"),
+                    Value::from(key.clone()),
+                    child,
+                )
+            })
+            .collect(),
+        Value::Array(array) => array
+            .iter()
+            .enumerate()
+            .map(|(i, child)| {
+                (
+                    format!("{pointer}/{i}"),
+                    format!("{path}[{i}]"),
+                    Value::from(i),
+                    child,
+                )
+            })
+            .collect(),
+        _ => vec![],
+    };
+    for (child_pointer, child_path, key, child) in children {
+        let child_idx = nodes.len();
+        add_nodes(nodes, child, child_pointer, child_path, key);
+        nodes[child_idx].parent = Some(nodes[parent].path.clone());
+    }
+}
+
+/// Type name as returned by `json_tree`
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(true) => "true",
+        Value::Bool(false) => "false",
+        Value::Number(number) if number.is_i64() => "integer",
+        Value::Number(_) => "real",
+        Value::String(_) => "text",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+#[repr(C)]
+struct QueryFunctionCursor<'vtab> {
+    /// Base class. Must be first
+    base: ffi::sqlite3_vtab_cursor,
+    /// `data` of the response
+    data: Value,
+    nodes: Vec<Node>,
+    /// `url`, `query` and `variables` arguments
+    arguments: [Option<String>; 3],
+    row_number: usize,
+    phantom: PhantomData<&'vtab QueryFunctionTab>,
+}
+
+unsafe impl VTabCursor for QueryFunctionCursor<'_> {
+    fn filter(&mut self, idx_num: c_int, _idx_str: Option<&str>, args: &Filters<'_>) -> Result<()> {
+        self.data = Value::Null;
+        self.nodes.clear();
+        self.row_number = 0;
+
+        let mut arguments: [Option<String>; 3] = [None, None, None];
+        let mut i = 0;
+        for (bit, argument) in arguments.iter_mut().enumerate() {
+            if idx_num & (1 << bit) != 0 {
+                *argument = args.get(i)?;
+                i += 1;
+            }
+        }
+        self.arguments = arguments.clone();
+        let [Some(url), Some(query), variables] = arguments else {
+            return Err(Error::ModuleError(
+                "graphql_query requires the `url` and `query` arguments".to_owned(),
+            ));
+        };
+        self.data = fetch(url, &query, variables.as_deref(), None)?;
+        add_nodes(
+            &mut self.nodes,
+            &self.data,
+            String::new(),
+            "$".to_owned(),
+            Value::Null,
+        );
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<()> {
+        self.row_number += 1;
+        Ok(())
+    }
+
+    fn eof(&self) -> bool {
+        self.row_number >= self.nodes.len()
+    }
+
+    fn column(&self, ctx: &mut Context, col: c_int) -> Result<()> {
+        let Some(node) = self.nodes.get(self.row_number) else {
+            return Err(Error::ModuleError("no current row".to_owned()));
+        };
+        let value = self.data.pointer(&node.pointer).unwrap_or(&Value::Null);
+        match col {
+            0 => ctx.set_result(&node.path),
+            1 => ctx.set_result(&to_sql_value(&node.key)),
+            2 => ctx.set_result(&to_sql_value(value)),
+            3 => ctx.set_result(&type_name(value)),
+            4 => ctx.set_result(&node.parent),
+            _ => match self.arguments.get(col as usize - URL_COL) {
+                Some(argument) => ctx.set_result(argument),
+                None => Err(Error::ModuleError(format!(
+                    "column index out of bounds: {col}"
+                ))),
+            },
+        }
+    }
+
+    fn rowid(&self) -> Result<i64> {
+        Ok(self.row_number as i64)
+    }
+}
@@ -0,0 +1,680 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::io::Read;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+
+use serde::de::{
+    self, value::SeqAccessDeserializer, DeserializeSeed, IgnoredAny, MapAccess, SeqAccess, Visitor,
+};
+use serde::Deserialize;
+use serde_json::Value;
+
+/// Number of list items that are decoded ahead of the cursor
+const CHANNEL_CAPACITY: usize = 64;
+
+/// Events emitted while a response is decoded
+pub enum Event {
+    /// The operation result without the items of the exploded list.
+    /// Sent before the first item.
+    Template {
+        /// Index of the endpoint
+        root: usize,
+        template: Value,
+        list_path: Vec<String>,
+    },
+    /// An item of the exploded list
+    Item(Value),
+    /// The result of an endpoint has been fully decoded
+    End {
+        root: usize,
+        /// The operation result without the items of the exploded list
+        result: Value,
+        list_path: Option<Vec<String>>,
+        item_count: usize,
+    },
+    /// The response has been fully decoded
+    Done,
+    Error(String),
+}
+
+/// Tree of the selected fields (response keys)
+#[derive(Default)]
+struct Node {
+    children: BTreeMap<String, Node>,
+}
+
+impl Node {
+    fn from_paths(paths: &[Vec<String>]) -> Node {
+        let mut root = Node::default();
+        for path in paths {
+            let mut node = &mut root;
+            for segment in path {
+                node = node.children.entry(segment.clone()).or_default();
+            }
+        }
+        root
+    }
+
+    fn is_leaf(&self) -> bool {
+        self.children.is_empty()
+    }
+}
+
+fn set_at(root: &mut Value, path: &[String], value: Value) {
+    let Some((last, parents)) = path.split_last() else {
+        *root = value;
+        return;
+    };
+    let mut current = root;
+    for segment in parents {
+        if !current.is_object() {
+            *current = Value::Object(Default::default());
+        }
+        current = current
+            .as_object_mut()
+            .unwrap()
+            .entry(segment.clone())
+            .or_insert(Value::Null);
+    }
+    if !current.is_object() {
+        *current = Value::Object(Default::default());
+    }
+    current.as_object_mut().unwrap().insert(last.clone(), value);
+}
+
+/// Decoding state of a single response
+struct Walker {
+    leaf_paths: Vec<Vec<String>>,
+    /// Only a list at or above this path is exploded, None for the first list
+    rows_path: Option<Vec<String>>,
+    sender: SyncSender<Event>,
+    /// Index of the endpoint that is decoded
+    root: usize,
+    /// Endpoints whose results have been decoded
+    decoded_roots: HashSet<usize>,
+    template: Value,
+    list_path: Option<Vec<String>>,
+    /// Paths whose values have been fully decoded
+    completed: HashSet<Vec<String>>,
+    /// Items are buffered if values outside the list arrive after the list
+    buffer: Option<Vec<Value>>,
+    item_count: usize,
+    errors: Option<Value>,
+}
+
+impl Walker {
+    fn set_value(&mut self, path: &[String], value: Value) {
+        set_at(&mut self.template, path, value);
+        self.completed.insert(path.to_vec());
+    }
+
+    fn is_complete(&self, path: &[String]) -> bool {
+        (0..=path.len()).any(|len| self.completed.contains(&path[..len]))
+    }
+
+    fn start_list(&mut self, path: &[String]) -> Result<(), String> {
+        self.list_path = Some(path.to_vec());
+        set_at(&mut self.template, path, Value::Array(vec![]));
+        let template_complete = self
+            .leaf_paths
+            .iter()
+            .filter(|it| !it.starts_with(path))
+            .all(|it| self.is_complete(it));
+        if !template_complete {
+            self.buffer = Some(vec![]);
+            return Ok(());
+        }
+        self.send(Event::Template {
+            root: self.root,
+            template: self.template.clone(),
+            list_path: path.to_vec(),
+        })
+    }
+
+    fn item(&mut self, item: Value) -> Result<(), String> {
+        self.item_count += 1;
+        match &mut self.buffer {
+            Some(buffer) => {
+                buffer.push(item);
+                Ok(())
+            }
+            None => self.send(Event::Item(item)),
+        }
+    }
+
+    fn send(&self, event: Event) -> Result<(), String> {
+        // fails if the cursor has been closed
+        self.sender
+            .send(event)
+            .map_err(|_| "response stream closed".to_owned())
+    }
+
+    fn start_root(&mut self, root: usize) {
+        self.root = root;
+        self.template = Value::Null;
+        self.list_path = None;
+        self.completed.clear();
+        self.buffer = None;
+        self.item_count = 0;
+    }
+
+    fn end_root(&mut self) -> Result<(), String> {
+        if let (Some(buffer), Some(list_path)) = (self.buffer.take(), &self.list_path) {
+            self.send(Event::Template {
+                root: self.root,
+                template: self.template.clone(),
+                list_path: list_path.clone(),
+            })?;
+            for item in buffer {
+                self.send(Event::Item(item))?;
+            }
+        }
+        self.decoded_roots.insert(self.root);
+        let end = Event::End {
+            root: self.root,
+            result: std::mem::take(&mut self.template),
+            list_path: self.list_path.take(),
+            item_count: self.item_count,
+        };
+        self.send(end)
+    }
+
+    fn finish(mut self, root_count: usize) -> Result<(), String> {
+        if let Some(errors) = &self.errors {
+            return self.send(Event::Error(errors.to_string()));
+        }
+        // endpoints missing in the response have a null result
+        for root in 0..root_count {
+            if !self.decoded_roots.contains(&root) {
+                self.start_root(root);
+                self.end_root()?;
+            }
+        }
+        self.send(Event::Done)
+    }
+}
+
+/// Decodes a value of the operation result
+struct ValueSeed<'n, 'w> {
+    node: &'n Node,
+    walker: &'w mut Walker,
+    path: Vec<String>,
+}
+
+impl<'de> DeserializeSeed<'de> for ValueSeed<'_, '_> {
+    type Value = ();
+
+    fn deserialize<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl ValueSeed<'_, '_> {
+    fn set<E>(self, value: Value) -> Result<(), E> {
+        self.walker.set_value(&self.path, value);
+        Ok(())
+    }
+}
+
+impl<'de> Visitor<'de> for ValueSeed<'_, '_> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a GraphQL result value")
+    }
+
+    fn visit_bool<E>(self, v: bool) -> Result<(), E> {
+        self.set(Value::Bool(v))
+    }
+
+    fn visit_i64<E>(self, v: i64) -> Result<(), E> {
+        self.set(Value::from(v))
+    }
+
+    fn visit_u64<E>(self, v: u64) -> Result<(), E> {
+        self.set(Value::from(v))
+    }
+
+    fn visit_f64<E>(self, v: f64) -> Result<(), E> {
+        self.set(Value::from(v))
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<(), E> {
+        self.set(Value::String(v.to_owned()))
+    }
+
+    fn visit_string<E>(self, v: String) -> Result<(), E> {
+        self.set(Value::String(v))
+    }
+
+    fn visit_unit<E>(self) -> Result<(), E> {
+        self.set(Value::Null)
+    }
+
+    fn visit_none<E>(self) -> Result<(), E> {
+        self.set(Value::Null)
+    }
+
+    fn visit_some<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_any(self)
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        set_at(
+            &mut self.walker.template,
+            &self.path,
+            Value::Object(Default::default()),
+        );
+        while let Some(key) = map.next_key::<String>()? {
+            let Some(child) = self.node.children.get(&key) else {
+                map.next_value::<IgnoredAny>()?;
+                continue;
+            };
+            let mut path = self.path.clone();
+            path.push(key);
+            if child.is_leaf() {
+                let value = map.next_value::<Value>()?;
+                self.walker.set_value(&path, value);
+            } else {
+                map.next_value_seed(ValueSeed {
+                    node: child,
+                    walker: self.walker,
+                    path,
+                })?;
+            }
+        }
+        self.walker.completed.insert(self.path);
+        Ok(())
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        // only the first list on the result paths (or on the rows path) is exploded
+        let explode = self.walker.list_path.is_none()
+            && match &self.walker.rows_path {
+                Some(rows_path) => rows_path.starts_with(&self.path),
+                None => true,
+            };
+        if !explode {
+            let value = Value::deserialize(SeqAccessDeserializer::new(seq))?;
+            return self.set(value);
+        }
+        self.walker
+            .start_list(&self.path)
+            .map_err(de::Error::custom)?;
+        while let Some(item) = seq.next_element::<Value>()? {
+            self.walker.item(item).map_err(de::Error::custom)?;
+        }
+        self.walker.completed.insert(self.path);
+        Ok(())
+    }
+}
+
+/// Decodes the `data` object of the response
+struct DataSeed<'n, 'w> {
+    endpoints: &'n [String],
+    root: &'n Node,
+    walker: &'w mut Walker,
+}
+
+impl<'de> DeserializeSeed<'de> for DataSeed<'_, '_> {
+    type Value = ();
+
+    fn deserialize<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> Visitor<'de> for DataSeed<'_, '_> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a GraphQL data object")
+    }
+
+    fn visit_unit<E>(self) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        while let Some(key) = map.next_key::<String>()? {
+            let Some(root) = self.endpoints.iter().position(|it| *it == key) else {
+                map.next_value::<IgnoredAny>()?;
+                continue;
+            };
+            self.walker.start_root(root);
+            map.next_value_seed(ValueSeed {
+                node: self.root,
+                walker: self.walker,
+                path: vec![],
+            })?;
+            self.walker.end_root().map_err(de::Error::custom)?;
+        }
+        Ok(())
+    }
+}
+
+/// Decodes the top level response object
+struct ResponseSeed<'n, 'w> {
+    endpoints: &'n [String],
+    root: &'n Node,
+    walker: &'w mut Walker,
+}
+
+impl<'de> DeserializeSeed<'de> for ResponseSeed<'_, '_> {
+    type Value = ();
+
+    fn deserialize<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for ResponseSeed<'_, '_> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a GraphQL response")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "data" => map.next_value_seed(DataSeed {
+                    endpoints: self.endpoints,
+                    root: self.root,
+                    walker: self.walker,
+                })?,
+                "errors" => self.walker.errors = Some(map.next_value::<Value>()?),
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Decodes a GraphQL response incrementally.
+///
+/// The response is decoded in a background thread and the items of the exploded list, i.e. the
+/// first list on the result paths or the outermost list on the `rows_path`, are passed on one by
+/// one. Only a bounded number of items is
+/// decoded ahead of the reader.
+///
+/// The response may contain several endpoints (response keys) with the same selection, e.g. the
+/// aliased copies of a batched query.
+pub struct ResponseStream {
+    receiver: Receiver<Event>,
+}
+
+impl ResponseStream {
+    /// `result_paths` are the response keys of the selected leaf fields relative to the endpoint
+    pub fn new<R: Read + Send + 'static>(
+        reader: R,
+        endpoints: Vec<String>,
+        result_paths: Vec<Vec<String>>,
+        rows_path: Option<Vec<String>>,
+    ) -> ResponseStream {
+        let (sender, receiver) = sync_channel(CHANNEL_CAPACITY);
+        std::thread::spawn(move || {
+            let root = Node::from_paths(&result_paths);
+            let mut walker = Walker {
+                leaf_paths: result_paths,
+                rows_path,
+                sender: sender.clone(),
+                root: 0,
+                decoded_roots: HashSet::new(),
+                template: Value::Null,
+                list_path: None,
+                completed: HashSet::new(),
+                buffer: None,
+                item_count: 0,
+                errors: None,
+            };
+            let mut deserializer = serde_json::Deserializer::from_reader(reader);
+            let result = ResponseSeed {
+                endpoints: &endpoints,
+                root: &root,
+                walker: &mut walker,
+            }
+            .deserialize(&mut deserializer)
+            .and_then(|_| deserializer.end());
+            let result = match result {
+                Ok(_) => walker.finish(endpoints.len()),
+                Err(err) if err.is_io() => Err(format!(
+                    "failed to read the response: {}",
+                    err.io_error_kind()
+                        .map(|it| it.to_string())
+                        .unwrap_or_default()
+                )),
+                Err(err) => Err(format!("invalid response: {err}")),
+            };
+            if let Err(err) = result {
+                // fails if the cursor has been closed, nothing left to do in this case
+                let _ = sender.send(Event::Error(err));
+            }
+        });
+        ResponseStream { receiver }
+    }
+
+    /// Blocks until the next event is available
+    pub fn next_event(&self) -> Event {
+        self.receiver
+            .recv()
+            .unwrap_or_else(|_| Event::Error("response stream closed".to_owned()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::{Event, ResponseStream};
+
+    fn paths(paths: &[&str]) -> Vec<Vec<String>> {
+        paths
+            .iter()
+            .map(|it| it.split('.').map(|it| it.to_owned()).collect())
+            .collect()
+    }
+
+    fn collect(stream: &ResponseStream) -> (Vec<String>, Option<serde_json::Value>) {
+        let mut events = vec![];
+        let mut last_result = None;
+        loop {
+            match stream.next_event() {
+                Event::Template {
+                    root, list_path, ..
+                } => events.push(format!("template {root} {}", list_path.join("."))),
+                Event::Item(item) => events.push(format!("item {item}")),
+                Event::End { root, result, .. } => {
+                    events.push(format!("end {root}"));
+                    last_result = Some(result);
+                }
+                Event::Done => return (events, last_result),
+                Event::Error(err) => {
+                    events.push(format!("error {err}"));
+                    return (events, None);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_stream_list_items() {
+        let response = json!({"data": {"orders": {
+            "totalCount": 2,
+            "nodes": [{"id": 1, "other": true}, {"id": 2}],
+            "ignored": "x"
+        }}});
+        let stream = ResponseStream::new(
+            std::io::Cursor::new(response.to_string()),
+            vec!["orders".to_owned()],
+            paths(&["totalCount", "nodes.id"]),
+            None,
+        );
+        let (events, result) = collect(&stream);
+        assert_eq!(
+            events,
+            vec![
+                "template 0 nodes".to_owned(),
+                r#"item {"id":1,"other":true}"#.to_owned(),
+                r#"item {"id":2}"#.to_owned(),
+                "end 0".to_owned(),
+            ]
+        );
+        assert_eq!(result, Some(json!({"totalCount": 2, "nodes": []})));
+    }
+
+    #[test]
+    fn test_stream_late_template_values() {
+        // pageInfo arrives after the list, the items are only passed on at the end
+        let response = json!({"data": {"orders": {
+            "edges": [{"node": {"id": 1}}],
+            "pageInfo": {"hasNextPage": false}
+        }}});
+        let stream = ResponseStream::new(
+            std::io::Cursor::new(response.to_string()),
+            vec!["orders".to_owned()],
+            paths(&["edges.node.id", "pageInfo.hasNextPage"]),
+            None,
+        );
+        let (events, result) = collect(&stream);
+        assert_eq!(
+            events,
+            vec![
+                "template 0 edges".to_owned(),
+                r#"item {"node":{"id":1}}"#.to_owned(),
+                "end 0".to_owned(),
+            ]
+        );
+        assert_eq!(
+            result,
+            Some(json!({"edges": [], "pageInfo": {"hasNextPage": false}}))
+        );
+    }
+
+    #[test]
+    fn test_stream_rows_path() {
+        // `tags` comes first but only the list on the rows path is exploded
+        let response = json!({"data": {"order": {
+            "tags": [{"name": "new"}],
+            "lines": [{"item": "a"}, {"item": "b"}]
+        }}});
+        let stream = ResponseStream::new(
+            std::io::Cursor::new(response.to_string()),
+            vec!["order".to_owned()],
+            paths(&["tags.name", "lines.item"]),
+            Some(paths(&["lines"]).remove(0)),
+        );
+        let (events, result) = collect(&stream);
+        assert_eq!(
+            events,
+            vec![
+                "template 0 lines".to_owned(),
+                r#"item {"item":"a"}"#.to_owned(),
+                r#"item {"item":"b"}"#.to_owned(),
+                "end 0".to_owned(),
+            ]
+        );
+        assert_eq!(
+            result,
+            Some(json!({"tags": [{"name": "new"}], "lines": []}))
+        );
+    }
+
+    /// A response that arrives in chunks
+    struct ChunkReader {
+        chunks: std::sync::mpsc::Receiver<Vec<u8>>,
+        current: std::io::Cursor<Vec<u8>>,
+    }
+
+    impl std::io::Read for ChunkReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            loop {
+                let read = self.current.read(buf)?;
+                if read > 0 {
+                    return Ok(read);
+                }
+                match self.chunks.recv() {
+                    Ok(chunk) => self.current = std::io::Cursor::new(chunk),
+                    Err(_) => return Ok(0),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_stream_items_before_end() {
+        // `totalCount` was removed from the query, waiting for it would buffer the whole list
+        let (sender, chunks) = std::sync::mpsc::channel();
+        let reader = ChunkReader {
+            chunks,
+            current: std::io::Cursor::new(vec![]),
+        };
+        let stream = ResponseStream::new(
+            reader,
+            vec!["orders".to_owned()],
+            paths(&["nodes.id"]),
+            None,
+        );
+        sender
+            .send(br#"{"data": {"orders": {"nodes": [{"id": 1}, "#.to_vec())
+            .unwrap();
+        assert!(matches!(stream.next_event(), Event::Template { .. }));
+        assert!(matches!(stream.next_event(), Event::Item(item) if item == json!({"id": 1})));
+        sender.send(br#"{"id": 2}]}}}"#.to_vec()).unwrap();
+        drop(sender);
+        let (events, _) = collect(&stream);
+        assert_eq!(
+            events,
+            vec![r#"item {"id":2}"#.to_owned(), "end 0".to_owned()]
+        );
+    }
+
+    #[test]
+    fn test_stream_errors() {
+        let response = json!({"errors": [{"message": "failed"}], "data": null});
+        let stream = ResponseStream::new(
+            std::io::Cursor::new(response.to_string()),
+            vec!["orders".to_owned()],
+            paths(&["id"]),
+            None,
+        );
+        let (events, result) = collect(&stream);
+        assert_eq!(events, vec![r#"error [{"message":"failed"}]"#.to_owned()]);
+        assert_eq!(result, None);
+    }
+
+    #[test]
+    fn test_stream_several_endpoints() {
+        // endpoints are passed on in the order of the response
+        let response = r#"{"data": {
+            "_apisql_1": {"nodes": [{"id": 3}]},
+            "_apisql_0": {"nodes": [{"id": 1}]}
+        }}"#;
+        let stream = ResponseStream::new(
+            std::io::Cursor::new(response),
+            vec![
+                "_apisql_0".to_owned(),
+                "_apisql_1".to_owned(),
+                "_apisql_2".to_owned(),
+            ],
+            paths(&["nodes.id"]),
+            None,
+        );
+        let (events, result) = collect(&stream);
+        assert_eq!(
+            events,
+            vec![
+                "template 1 nodes".to_owned(),
+                r#"item {"id":3}"#.to_owned(),
+                "end 1".to_owned(),
+                "template 0 nodes".to_owned(),
+                r#"item {"id":1}"#.to_owned(),
+                "end 0".to_owned(),
+                "end 2".to_owned(),
+            ]
+        );
+        assert_eq!(result, Some(json!(null)));
+    }
+}
@@ -0,0 +1,332 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use reqwest::{
+    blocking::{Client, RequestBuilder, Response},
+    header::{HeaderName, HeaderValue},
+};
+use rusqlite::{Error, Result};
+use serde_json::Value;
+
+const DEFAULT_API_KEY_HEADER: &str = "X-API-Key";
+
+/// Environment variable with the `,` separated secret references that may be resolved, e.g.
+/// `env:GRAPHQL_TOKEN,file:/run/secrets`. A `file:` entry allows the file or the files in the
+/// directory.
+const SECRET_REFERENCES_VAR: &str = "APISQL_SECRET_REFERENCES";
+
+/// The secret references allowed by the process environment
+fn allowed_references() -> Vec<String> {
+    std::env::var(SECRET_REFERENCES_VAR)
+        .unwrap_or_default()
+        .split(',')
+        .map(|it| it.trim().to_owned())
+        .filter(|it| !it.is_empty())
+        .collect()
+}
+
+/// True if the reference is in the `allowed` list, file paths are compared after resolving `..`
+/// and symbolic links
+fn is_allowed(value: &str, allowed: &[String]) -> bool {
+    if let Some(path) = value.strip_prefix("file:") {
+        let Ok(path) = Path::new(path).canonicalize() else {
+            return false;
+        };
+        return allowed
+            .iter()
+            .filter_map(|it| it.strip_prefix("file:"))
+            .filter_map(|it| Path::new(it).canonicalize().ok())
+            .any(|it| path.starts_with(it));
+    }
+    allowed.iter().any(|it| it == value)
+}
+
+/// Resolves secret references in the values of the credential options `url`, `bearer_token`,
+/// `basic_auth` and `api_key`:
+/// - `env:NAME` the value of the environment variable `NAME`
+/// - `file:PATH` the content of the file at `PATH`, e.g. a mounted secret
+///
+/// Other values are returned as they are.
+/// The table options are stored in the database file, so a reference is only resolved if the
+/// process allows it in `allowed` (see `APISQL_SECRET_REFERENCES`). Otherwise opening an untrusted
+/// database could send local files or environment variables to a server of its choice.
+/// Errors only mention the reference and never the resolved value.
+fn resolve_secret(option: &str, value: &str, allowed: &[String]) -> Result<String> {
+    if is_secret_reference(value) && !is_allowed(value, allowed) {
+        return Err(Error::ModuleError(format!(
+            "secret reference `{value}` of `{option}` is not allowed, add it to \
+             {SECRET_REFERENCES_VAR}"
+        )));
+    }
+    if let Some(name) = value.strip_prefix("env:") {
+        return std::env::var(name).map_err(|_| {
+            Error::ModuleError(format!(
+                "environment variable `{name}` referenced by `{option}` is not set"
+            ))
+        });
+    }
+    if let Some(path) = value.strip_prefix("file:") {
+        let content = std::fs::read_to_string(path).map_err(|err| {
+            Error::ModuleError(format!(
+                "failed to read `{path}` referenced by `{option}`: {}",
+                err.kind()
+            ))
+        })?;
+        return Ok(content.trim_end_matches(['\r', '\n']).to_owned());
+    }
+    Ok(value.to_owned())
+}
+
+fn is_secret_reference(value: &str) -> bool {
+    value.starts_with("env:") || value.starts_with("file:")
+}
+
+/// Connection details of a GraphQL server.
+///
+/// The credential options may be secret references (see `resolve_secret`), these are only
+/// resolved when a request is sent so that secrets are never stored in the `sqlite_schema`
+/// table. Header values are sent as they are, a database schema can't make them read arbitrary
+/// files or environment variables.
+#[derive(Default, Clone)]
+pub struct Transport {
+    pub url: String,
+    /// Additional HTTP headers sent with every request
+    pub headers: Vec<(String, String)>,
+    pub bearer_token: Option<String>,
+    /// `user:password` pair
+    pub basic_auth: Option<String>,
+    pub api_key: Option<String>,
+    /// Header used to send the `api_key`, defaults to `X-API-Key`
+    pub api_key_header: Option<String>,
+    /// Shared by the clones of the transport, connections are reused across pages
+    client: Client,
+}
+
+impl Transport {
+    /// Applies a connection related module option.
+    /// Returns false if the option is not a connection option.
+    pub fn set_option(&mut self, param: &str, value: &str) -> Result<bool> {
+        match param {
+            "url" => self.url = value.to_owned(),
+            "headers" => {
+                let headers: BTreeMap<String, String> =
+                    serde_json::from_str(value).map_err(|err| {
+                        Error::ModuleError(format!(
+                            "`headers` must be a JSON object of strings: {err}"
+                        ))
+                    })?;
+                self.headers.extend(headers);
+            }
+            "bearer_token" => self.bearer_token = Some(value.to_owned()),
+            "basic_auth" => self.basic_auth = Some(value.to_owned()),
+            "api_key" => self.api_key = Some(value.to_owned()),
+            "api_key_header" => self.api_key_header = Some(value.to_owned()),
+            _ => {
+                let Some(name) = param.strip_prefix("header_") else {
+                    return Ok(false);
+                };
+                self.headers.push((name.to_owned(), value.to_owned()));
+            }
+        }
+        Ok(true)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.url.is_empty() {
+            return Err(Error::ModuleError("no server `url` specified".to_owned()));
+        }
+        // fail when the table is created instead of at the first request
+        let api_key_header = self.api_key_header.iter().map(|it| (it, None));
+        let headers = self.headers.iter().map(|(name, value)| (name, Some(value)));
+        for (name, value) in headers.chain(api_key_header) {
+            if HeaderName::from_bytes(name.as_bytes()).is_err() {
+                return Err(Error::ModuleError(format!("invalid header name `{name}`")));
+            }
+            if value.is_some_and(|it| HeaderValue::from_str(it).is_err()) {
+                return Err(Error::ModuleError(format!(
+                    "invalid value of header `{name}`"
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Posts a request to the server and returns the response.
+    /// Fails if the response contains errors.
+    pub fn post(&self, body: &Value) -> Result<Value> {
+        let res = self
+            .send(body)?
+            .json::<serde_json::Value>()
+            .map_err(|err| Error::ModuleError(err.to_string()))?;
+
+        if let Some(errors) = res.get("errors") {
+            return Err(Error::ModuleError(errors.to_string()));
+        };
+        Ok(res)
+    }
+
+    /// Builds the request with the resolved credentials
+    fn request(&self, body: &Value) -> Result<RequestBuilder> {
+        let allowed = allowed_references();
+        let mut request = self
+            .client
+            .post(resolve_secret("url", &self.url, &allowed)?)
+            .json(body);
+        for (name, value) in &self.headers {
+            request = request.header(name, value);
+        }
+        if let Some(token) = &self.bearer_token {
+            request = request.bearer_auth(resolve_secret("bearer_token", token, &allowed)?);
+        }
+        if let Some(basic_auth) = &self.basic_auth {
+            let basic_auth = resolve_secret("basic_auth", basic_auth, &allowed)?;
+            let Some((user, password)) = basic_auth.split_once(':') else {
+                return Err(Error::ModuleError(
+                    "`basic_auth` must have the form `user:password`".to_owned(),
+                ));
+            };
+            request = request.basic_auth(user, Some(password));
+        }
+        if let Some(api_key) = &self.api_key {
+            let header = self
+                .api_key_header
+                .as_deref()
+                .unwrap_or(DEFAULT_API_KEY_HEADER);
+            request = request.header(header, resolve_secret("api_key", api_key, &allowed)?);
+        }
+        Ok(request)
+    }
+
+    /// Posts a request to the server and returns the response without reading the body.
+    /// Fails if the server doesn't respond with a success status.
+    pub fn send(&self, body: &Value) -> Result<Response> {
+        let response = self.request(body)?.send().map_err(|err| {
+            // don't leak a url that has been resolved from a secret reference
+            if is_secret_reference(&self.url) {
+                Error::ModuleError(err.without_url().to_string())
+            } else {
+                Error::ModuleError(err.to_string())
+            }
+        })?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        // GraphQL servers may respond with an error status and the errors in the body
+        match response.json::<serde_json::Value>() {
+            Ok(res) if res.get("errors").is_some() => {
+                Err(Error::ModuleError(res["errors"].to_string()))
+            }
+            _ => Err(Error::ModuleError(format!(
+                "request failed with status {status}"
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::{resolve_secret, Transport};
+
+    #[test]
+    fn test_set_option() {
+        let mut transport = Transport::default();
+        assert!(transport
+            .set_option("url", "http://localhost:8000/graphql")
+            .unwrap());
+        assert!(transport.set_option("header_X-Tenant", "abc").unwrap());
+        assert!(transport
+            .set_option("headers", r#"{"X-Store": "store1"}"#)
+            .unwrap());
+        assert!(transport.set_option("basic_auth", "user:pw").unwrap());
+        assert!(!transport.set_option("query", "query Q { a }").unwrap());
+        assert!(transport.set_option("headers", "[]").is_err());
+        assert_eq!(
+            transport.headers,
+            vec![
+                ("X-Tenant".to_owned(), "abc".to_owned()),
+                ("X-Store".to_owned(), "store1".to_owned())
+            ]
+        );
+        transport.validate().unwrap();
+
+        transport
+            .headers
+            .push(("X Tenant".to_owned(), "abc".to_owned()));
+        assert!(transport.validate().is_err());
+        transport.headers.pop();
+        transport
+            .headers
+            .push(("X-Tenant".to_owned(), "a\nb".to_owned()));
+        assert!(transport.validate().is_err());
+    }
+
+    #[test]
+    fn test_header_values_are_not_resolved() {
+        std::env::set_var("APISQL_TEST_API_KEY", "secret-key");
+        std::env::set_var("APISQL_SECRET_REFERENCES", "env:APISQL_TEST_API_KEY");
+        let mut transport = Transport::default();
+        transport
+            .set_option("url", "http://localhost:8000/graphql")
+            .unwrap();
+        transport
+            .set_option("header_X-Path", "file:/etc/passwd")
+            .unwrap();
+        transport
+            .set_option("header_X-Env", "env:APISQL_TEST_API_KEY")
+            .unwrap();
+        transport
+            .set_option("api_key", "env:APISQL_TEST_API_KEY")
+            .unwrap();
+        let request = transport.request(&json!({})).unwrap().build().unwrap();
+        let header = |name: &str| request.headers()[name].to_str().unwrap().to_owned();
+        assert_eq!(header("X-Path"), "file:/etc/passwd");
+        assert_eq!(header("X-Env"), "env:APISQL_TEST_API_KEY");
+        assert_eq!(header("X-API-Key"), "secret-key");
+    }
+
+    #[test]
+    fn test_resolve_secret() {
+        std::env::set_var("APISQL_TEST_TOKEN", "secret-token");
+        let dir = std::env::temp_dir().join("apisql_test_secrets");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("api_key");
+        std::fs::write(&path, "file-secret\n").unwrap();
+        let allowed = vec![
+            "env:APISQL_TEST_TOKEN".to_owned(),
+            "env:APISQL_TEST_MISSING".to_owned(),
+            format!("file:{}", dir.display()),
+        ];
+
+        assert_eq!(
+            resolve_secret("bearer_token", "env:APISQL_TEST_TOKEN", &allowed).unwrap(),
+            "secret-token"
+        );
+        assert_eq!(resolve_secret("api_key", "plain", &[]).unwrap(), "plain");
+        assert_eq!(
+            resolve_secret("api_key", &format!("file:{}", path.display()), &allowed).unwrap(),
+            "file-secret"
+        );
+
+        let err = resolve_secret("bearer_token", "env:APISQL_TEST_MISSING", &allowed).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "environment variable `APISQL_TEST_MISSING` referenced by `bearer_token` is not set"
+        );
+
+        // references that the process doesn't allow aren't resolved
+        let err = resolve_secret("bearer_token", "env:APISQL_TEST_TOKEN", &[]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "secret reference `env:APISQL_TEST_TOKEN` of `bearer_token` is not allowed, add it \
+             to APISQL_SECRET_REFERENCES"
+        );
+        let escaped = format!("file:{}/../apisql_test_secrets_other", dir.display());
+        std::fs::write(dir.join("../apisql_test_secrets_other"), "other").unwrap();
+        assert!(resolve_secret("api_key", &escaped, &allowed).is_err());
+        std::fs::remove_file(dir.join("../apisql_test_secrets_other")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}